@@ -1,2 +1,8 @@
 // Package mailer implements a client for the mailer service.
+//
+// Unsubscribe links are signed, so Client.Unsubscribe takes the token and expiry time from the
+// link the recipient followed, alongside the mailing list and address. This changed its
+// signature from Unsubscribe(mailingList, email); callers of the old form should pass through
+// the link's token and expires query parameters, since the service rejects unsubscribes without
+// a valid token.
 package mailer
@@ -4,10 +4,13 @@ import (
 	"fmt"
 	"net/http"
 	"net/url"
+	"strconv"
 )
 
-// Unsubscribe unsubscribes an email address from the given mailing list.
-func (c *Client) Unsubscribe(mailingList uint, email string) error {
+// Unsubscribe unsubscribes an email address from the given mailing list. The token and expiry
+// time are the ones from the unsubscribe link sent to the address; expires should be 0 if the
+// link has no expiry time.
+func (c *Client) Unsubscribe(mailingList uint, email, token string, expires int64) error {
 	client, err := c.client(false)
 	if err != nil {
 		return err
@@ -18,9 +21,15 @@ func (c *Client) Unsubscribe(mailingList uint, email string) error {
 		return err
 	}
 
-	resp, err := client.PostForm(c.baseURL.ResolveReference(rel).String(), url.Values{
+	form := url.Values{
 		"email": []string{email},
-	})
+		"token": []string{token},
+	}
+	if expires != 0 {
+		form.Set("expires", strconv.FormatInt(expires, 10))
+	}
+
+	resp, err := client.PostForm(c.baseURL.ResolveReference(rel).String(), form)
 	if err != nil {
 		return err
 	}
@@ -0,0 +1,5 @@
+ALTER TABLE mailer_queue
+	DROP COLUMN attempts,
+	DROP COLUMN last_error,
+	DROP COLUMN next_attempt,
+	DROP COLUMN failed;
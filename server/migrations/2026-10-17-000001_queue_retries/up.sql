@@ -0,0 +1,5 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN attempts INT UNSIGNED NOT NULL DEFAULT 0,
+	ADD COLUMN last_error TEXT NULL,
+	ADD COLUMN next_attempt TIMESTAMP NULL,
+	ADD COLUMN failed BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	DROP COLUMN claimed_by,
+	DROP COLUMN lease_expires;
@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN claimed_by VARCHAR(255) NULL,
+	ADD COLUMN lease_expires TIMESTAMP NULL;
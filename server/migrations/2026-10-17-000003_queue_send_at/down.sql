@@ -0,0 +1,2 @@
+ALTER TABLE mailer_queue
+	DROP COLUMN send_at;
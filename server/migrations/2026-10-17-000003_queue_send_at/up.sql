@@ -0,0 +1,2 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN send_at TIMESTAMP NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	DROP INDEX mailer_queue_claim_order,
+	DROP COLUMN priority;
@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN priority INT NOT NULL DEFAULT 0,
+	ADD INDEX mailer_queue_claim_order (priority DESC, id);
@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	DROP INDEX mailer_queue_idempotency_key,
+	DROP COLUMN idempotency_key;
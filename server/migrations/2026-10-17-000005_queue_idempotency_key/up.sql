@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN idempotency_key VARCHAR(255) NULL,
+	ADD UNIQUE INDEX mailer_queue_idempotency_key (idempotency_key);
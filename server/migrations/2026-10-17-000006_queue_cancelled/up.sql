@@ -0,0 +1,2 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN cancelled BOOLEAN NOT NULL DEFAULT FALSE;
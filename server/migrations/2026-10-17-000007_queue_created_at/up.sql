@@ -0,0 +1,2 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
@@ -0,0 +1,3 @@
+ALTER TABLE mailer_queue
+	DROP INDEX mailer_queue_sent_at,
+	DROP COLUMN sent_at;
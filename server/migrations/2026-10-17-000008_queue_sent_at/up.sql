@@ -0,0 +1,7 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN sent_at TIMESTAMP NULL,
+	ADD INDEX mailer_queue_sent_at (sent_at);
+
+-- The time emails sent before now were sent at isn't known, so they're counted as sent now, to
+-- keep retention policies from treating them as never sent.
+UPDATE mailer_queue SET sent_at = CURRENT_TIMESTAMP WHERE send_done AND sent_at IS NULL;
@@ -0,0 +1,6 @@
+DROP TRIGGER mailer_templates_delete;
+DROP TRIGGER mailer_templates_update;
+DROP TRIGGER mailer_templates_insert;
+
+ALTER TABLE mailer_lists
+	DROP COLUMN templates_version;
@@ -0,0 +1,20 @@
+ALTER TABLE mailer_lists
+	ADD COLUMN templates_version INT UNSIGNED NOT NULL DEFAULT 0;
+
+CREATE TRIGGER mailer_templates_insert AFTER INSERT ON mailer_templates
+	FOR EACH ROW
+	UPDATE mailer_lists
+		SET templates_version = templates_version + 1
+		WHERE id = NEW.mailing_list_id;
+
+CREATE TRIGGER mailer_templates_update AFTER UPDATE ON mailer_templates
+	FOR EACH ROW
+	UPDATE mailer_lists
+		SET templates_version = templates_version + 1
+		WHERE id IN (OLD.mailing_list_id, NEW.mailing_list_id);
+
+CREATE TRIGGER mailer_templates_delete AFTER DELETE ON mailer_templates
+	FOR EACH ROW
+	UPDATE mailer_lists
+		SET templates_version = templates_version + 1
+		WHERE id = OLD.mailing_list_id;
@@ -0,0 +1 @@
+DROP TABLE mailer_subscribers;
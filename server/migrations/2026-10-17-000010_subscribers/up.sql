@@ -0,0 +1,9 @@
+CREATE TABLE mailer_subscribers
+	( id INT UNSIGNED NOT NULL AUTO_INCREMENT
+	, mailing_list_id INT UNSIGNED NOT NULL
+	, email VARCHAR(255) NOT NULL
+	, created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+	, PRIMARY KEY (id)
+	, UNIQUE KEY mailer_subscribers_list_email (mailing_list_id, email)
+	, FOREIGN KEY (mailing_list_id) REFERENCES mailer_lists(id)
+	);
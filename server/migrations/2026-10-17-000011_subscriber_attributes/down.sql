@@ -0,0 +1,2 @@
+ALTER TABLE mailer_subscribers
+	DROP COLUMN attributes;
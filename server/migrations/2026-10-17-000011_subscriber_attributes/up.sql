@@ -0,0 +1,2 @@
+ALTER TABLE mailer_subscribers
+	ADD COLUMN attributes LONGTEXT NULL;
@@ -0,0 +1,8 @@
+ALTER TABLE mailer_subscribers
+	DROP INDEX mailer_subscribers_confirm_token,
+	DROP COLUMN confirm_token,
+	DROP COLUMN confirmed;
+
+ALTER TABLE mailer_lists
+	DROP FOREIGN KEY mailer_lists_confirm_template,
+	DROP COLUMN confirm_template_id;
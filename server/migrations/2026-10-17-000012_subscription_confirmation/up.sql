@@ -0,0 +1,9 @@
+ALTER TABLE mailer_lists
+	ADD COLUMN confirm_template_id INT UNSIGNED NULL,
+	ADD CONSTRAINT mailer_lists_confirm_template
+		FOREIGN KEY (confirm_template_id) REFERENCES mailer_templates(id);
+
+ALTER TABLE mailer_subscribers
+	ADD COLUMN confirmed BOOLEAN NOT NULL DEFAULT TRUE,
+	ADD COLUMN confirm_token VARCHAR(64) NULL,
+	ADD UNIQUE INDEX mailer_subscribers_confirm_token (confirm_token);
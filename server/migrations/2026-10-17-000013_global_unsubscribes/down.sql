@@ -0,0 +1,4 @@
+DELETE FROM mailer_unsubscribes WHERE mailing_list_id IS NULL;
+
+ALTER TABLE mailer_unsubscribes
+	MODIFY mailing_list_id INT UNSIGNED NOT NULL;
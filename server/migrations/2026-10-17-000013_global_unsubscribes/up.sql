@@ -0,0 +1,2 @@
+ALTER TABLE mailer_unsubscribes
+	MODIFY mailing_list_id INT UNSIGNED NULL;
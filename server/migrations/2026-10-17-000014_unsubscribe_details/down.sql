@@ -0,0 +1,5 @@
+ALTER TABLE mailer_unsubscribes
+	DROP INDEX mailer_unsubscribes_email_list,
+	DROP COLUMN mailing_list_key,
+	DROP COLUMN source,
+	DROP COLUMN unsubscribed_at;
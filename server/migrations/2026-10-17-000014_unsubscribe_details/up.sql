@@ -0,0 +1,13 @@
+DELETE later FROM mailer_unsubscribes later
+	INNER JOIN mailer_unsubscribes earlier
+		ON later.email = earlier.email
+		AND later.mailing_list_id <=> earlier.mailing_list_id
+		AND later.id > earlier.id;
+
+ALTER TABLE mailer_unsubscribes
+	ADD COLUMN unsubscribed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	ADD COLUMN source VARCHAR(16) NOT NULL DEFAULT 'link',
+	-- A unique index never treats NULLs as equal, so opt-outs from all mail (with a NULL list ID)
+	-- are indexed by this column instead, where their list ID is 0.
+	ADD COLUMN mailing_list_key INT UNSIGNED AS (COALESCE(mailing_list_id, 0)) STORED NOT NULL,
+	ADD UNIQUE INDEX mailer_unsubscribes_email_list (email, mailing_list_key);
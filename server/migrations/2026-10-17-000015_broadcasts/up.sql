@@ -0,0 +1,12 @@
+CREATE TABLE mailer_broadcasts
+	( id INT UNSIGNED NOT NULL AUTO_INCREMENT
+	, mailing_list_id INT UNSIGNED NOT NULL
+	, template_id INT UNSIGNED NOT NULL
+	, idempotency_key VARCHAR(255) NULL
+	, queued INT UNSIGNED NOT NULL
+	, created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+	, PRIMARY KEY (id)
+	, UNIQUE KEY mailer_broadcasts_idempotency_key (idempotency_key)
+	, FOREIGN KEY (mailing_list_id) REFERENCES mailer_lists(id)
+	, FOREIGN KEY (template_id) REFERENCES mailer_templates(id)
+	);
@@ -0,0 +1,2 @@
+ALTER TABLE mailer_queue
+	ADD COLUMN confirmation BOOLEAN NOT NULL DEFAULT FALSE;
@@ -2,12 +2,16 @@
 
 mod schema;
 
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration as StdDuration;
 
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
 use diesel::{
     self,
     prelude::*,
     r2d2::{ConnectionManager, Pool},
+    result::{DatabaseErrorKind, Error as DieselError},
 };
 use futures::{
     future::{err, poll_fn, Either},
@@ -17,8 +21,21 @@ use pulldown_cmark::{self, html::push_html};
 use tera::{Context, Tera};
 use tokio_threadpool::blocking;
 
-use db::schema::{mailer_lists, mailer_queue, mailer_templates, mailer_unsubscribes};
-use {Error, ErrorKind, Result};
+use db::schema::{
+    mailer_broadcasts, mailer_lists, mailer_queue, mailer_subscribers, mailer_templates,
+    mailer_unsubscribes,
+};
+use {metrics, Error, ErrorKind, Result, RetryPolicy};
+
+no_arg_sql_function!(
+    last_insert_id,
+    ::diesel::sql_types::Unsigned<::diesel::sql_types::Bigint>,
+    "Represents the MySQL LAST_INSERT_ID() function"
+);
+
+/// The priority of confirmation emails. They're sent ahead of ordinary mail, since someone is
+/// waiting for them.
+const CONFIRMATION_PRIORITY: i32 = 10;
 
 /// An HTML or Markdown document.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -30,17 +47,577 @@ pub enum TemplateContents {
     Markdown(String),
 }
 
+/// An email to be added to the queue.
+#[derive(Clone, Debug)]
+pub struct NewEmail {
+    /// The name of the mailing list to send on.
+    pub mailing_list: String,
+
+    /// The name of the template to render.
+    pub template: String,
+
+    /// The address to send to.
+    pub email: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The data to render into the template, as JSON.
+    pub data: String,
+
+    /// The time to send the email at. If `None`, it's sent as soon as possible.
+    pub send_at: Option<NaiveDateTime>,
+
+    /// The priority of the email. Emails with higher priorities are sent before those with lower
+    /// ones; emails with the same priority are sent in the order they were queued.
+    pub priority: i32,
+
+    /// A key chosen by the caller to identify the request to send this email. If an email with
+    /// the same key has already been queued, this one isn't.
+    pub idempotency_key: Option<String>,
+}
+
+/// An email to be added to the queue for every subscriber of a mailing list.
+#[derive(Clone, Debug)]
+pub struct NewBroadcast {
+    /// The name of the mailing list to send on.
+    pub mailing_list: String,
+
+    /// The name of the template to render.
+    pub template: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The data to render into the template, as JSON.
+    pub data: String,
+
+    /// The time to send the emails at. If `None`, they're sent as soon as possible.
+    pub send_at: Option<NaiveDateTime>,
+
+    /// The priority of the emails, as for `NewEmail`.
+    pub priority: i32,
+
+    /// A key identifying the request that made the broadcast, as for `NewEmail`.
+    pub idempotency_key: Option<String>,
+}
+
+/// The same email, to be added to the queue for many recipients.
+#[derive(Clone, Debug)]
+pub struct NewBulkEmail {
+    /// The name of the mailing list to send on.
+    pub mailing_list: String,
+
+    /// The name of the template to render.
+    pub template: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The time to send the emails at. If `None`, they're sent as soon as possible.
+    pub send_at: Option<NaiveDateTime>,
+
+    /// The priority of the emails, as for `NewEmail`.
+    pub priority: i32,
+
+    /// The recipients of the email.
+    pub recipients: Vec<Recipient>,
+}
+
+/// One recipient of a `NewBulkEmail`.
+#[derive(Clone, Debug)]
+pub struct Recipient {
+    /// The address to send to.
+    pub email: String,
+
+    /// The data to render into the template for this recipient, as JSON.
+    pub data: String,
+}
+
+/// An email that failed to send too many times, and won't be tried again.
+#[derive(Clone, Debug, Queryable)]
+pub struct FailedEmail {
+    /// The ID of the email in the queue.
+    pub id: u32,
+
+    /// The name of the mailing list the email was to be sent on.
+    pub mailing_list: String,
+
+    /// The name of the template.
+    pub template: String,
+
+    /// The address the email was to be sent to.
+    pub to_addr: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The time the email was queued.
+    pub created_at: NaiveDateTime,
+
+    /// The number of attempts made to send the email.
+    pub attempts: u32,
+
+    /// The error from the last attempt.
+    pub last_error: Option<String>,
+}
+
+/// Criteria for selecting failed emails. Each criterion that's present must match.
+#[derive(Clone, Debug, Default)]
+pub struct FailedFilter {
+    /// The name of the mailing list.
+    pub mailing_list: Option<String>,
+
+    /// The name of the template.
+    pub template: Option<String>,
+
+    /// The earliest time the email may have been queued at.
+    pub since: Option<NaiveDateTime>,
+
+    /// The latest time the email may have been queued at.
+    pub until: Option<NaiveDateTime>,
+
+    /// A string the error must contain.
+    pub error: Option<String>,
+}
+
+/// An email that has been sent.
+#[derive(Clone, Debug, Queryable)]
+pub struct SentEmail {
+    /// The ID of the email in the queue.
+    pub id: u32,
+
+    /// The name of the mailing list the email was sent on.
+    pub mailing_list: String,
+
+    /// The name of the template.
+    pub template: String,
+
+    /// The address the email was sent to.
+    pub to_addr: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The data rendered into the template, as JSON. This is empty if it has been purged.
+    pub data: String,
+
+    /// The time the email was queued.
+    pub created_at: NaiveDateTime,
+
+    /// The time the email was sent.
+    pub sent_at: Option<NaiveDateTime>,
+}
+
+/// An email that has been taken from the queue to be sent.
+#[derive(Clone, Debug, Queryable)]
+pub struct QueuedEmail {
+    /// The ID of the email in the queue.
+    pub id: u32,
+
+    /// The ID of the mailing list the email is being sent on.
+    pub mailing_list_id: u32,
+
+    /// The ID of the template to render.
+    pub template_id: u32,
+
+    /// The address to send to.
+    pub to_addr: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The data to render into the template, as JSON.
+    pub data: String,
+
+    /// The attributes of the recipient, as JSON, if they're a subscriber of the mailing list.
+    pub attributes: Option<String>,
+}
+
+/// The state of an email in the queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueState {
+    /// The email is waiting to be sent.
+    Pending,
+
+    /// The email is being sent.
+    Sending,
+
+    /// The email has been sent.
+    Sent,
+
+    /// Sending the email failed too many times, and it won't be tried again.
+    Failed,
+
+    /// The email was cancelled before it was sent.
+    Cancelled,
+
+    /// The email won't be sent, because the recipient has unsubscribed from the mailing list.
+    Suppressed,
+}
+
+/// The status of an email in the queue.
+#[derive(Clone, Debug)]
+pub struct QueueStatus {
+    /// The state the email is in.
+    pub state: QueueState,
+
+    /// The number of failed attempts to send the email.
+    pub attempts: u32,
+
+    /// The error from the last failed attempt to send the email, if any.
+    pub last_error: Option<String>,
+}
+
+/// An email in the queue that's scheduled to be sent later.
+#[derive(Clone, Debug)]
+pub struct ScheduledEmail {
+    /// The ID of the email in the queue.
+    pub id: u32,
+
+    /// The name of the mailing list the email will be sent on.
+    pub mailing_list: String,
+
+    /// The name of the template to render.
+    pub template: String,
+
+    /// The address to send to.
+    pub to_addr: String,
+
+    /// The subject line.
+    pub subject: String,
+
+    /// The time the email will be sent at.
+    pub send_at: NaiveDateTime,
+}
+
+/// A subscriber to a mailing list.
+#[derive(Clone, Debug, Queryable)]
+pub struct Subscriber {
+    /// The ID of the subscriber.
+    pub id: u32,
+
+    /// The subscriber's address.
+    pub email: String,
+
+    /// The subscriber's attributes, as JSON.
+    pub attributes: Option<String>,
+
+    /// The time the subscriber was added.
+    pub created_at: NaiveDateTime,
+
+    /// Whether the subscriber has confirmed their subscription. Subscribers who signed up
+    /// themselves aren't sent mail until they confirm.
+    pub confirmed: bool,
+}
+
+/// How an address came to be unsubscribed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnsubscribeSource {
+    /// The recipient followed an unsubscribe link, or changed their preferences.
+    Link,
+
+    /// The recipient's mail client sent a one-click unsubscribe request (RFC 8058).
+    OneClick,
+
+    /// An administrator unsubscribed the address.
+    Admin,
+
+    /// Mail to the address bounced.
+    Bounce,
+}
+
+impl UnsubscribeSource {
+    /// Returns the name the source is stored in the database as.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnsubscribeSource::Link => "link",
+            UnsubscribeSource::OneClick => "one-click",
+            UnsubscribeSource::Admin => "admin",
+            UnsubscribeSource::Bounce => "bounce",
+        }
+    }
+}
+
 /// A pool of connections to the database.
 #[derive(Clone)]
 pub struct DB {
     pool: Arc<Pool<ConnectionManager<MysqlConnection>>>,
+
+    /// The compiled templates of each mailing list, by mailing list ID, along with the version of
+    /// the list's templates they were compiled from.
+    templates: Arc<RwLock<HashMap<u32, (u32, Arc<Tera>)>>>,
 }
 
 impl DB {
     /// Connects to the database with the given number of connections.
     pub fn connect(database_url: &str) -> Result<DB> {
         let pool = Arc::new(Pool::new(ConnectionManager::new(database_url))?);
-        Ok(DB { pool })
+        let templates = Arc::new(RwLock::new(HashMap::new()));
+        Ok(DB { pool, templates })
+    }
+
+    /// Adds a subscriber to the mailing list with the given ID.
+    pub fn add_subscriber(
+        &self,
+        mailing_list_id: u32,
+        email: String,
+        attributes: String,
+    ) -> impl Future<Item = Subscriber, Error = Error> {
+        self.async_query(move |conn| {
+            conn.transaction(|| -> Result<_> {
+                check_mailing_list_exists(conn, mailing_list_id)?;
+                if find_subscriber(conn, mailing_list_id, &email)?.is_some() {
+                    return Err(ErrorKind::SubscriberExists(email.clone()).into());
+                }
+
+                let r = diesel::insert_into(mailer_subscribers::table)
+                    .values((
+                        mailer_subscribers::mailing_list_id.eq(mailing_list_id),
+                        mailer_subscribers::email.eq(&email),
+                        mailer_subscribers::attributes.eq(&attributes),
+                    ))
+                    .execute(conn);
+                match r {
+                    // Another request added the same subscriber since we checked.
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        return Err(ErrorKind::SubscriberExists(email.clone()).into())
+                    }
+                    r => r?,
+                };
+
+                find_subscriber(conn, mailing_list_id, &email)?
+                    .ok_or_else(|| ErrorKind::NoSuchSubscriber(email.clone()).into())
+            })
+        })
+    }
+
+    /// Adds an email to the queue for each confirmed subscriber of the mailing list who hasn't
+    /// unsubscribed from it, all in one transaction. Returns the number of emails queued.
+    ///
+    /// If the broadcast has an idempotency key, and a broadcast with the same key was already
+    /// made, nothing is queued, and the number of emails the original broadcast queued is
+    /// returned.
+    pub fn broadcast(&self, new: NewBroadcast) -> impl Future<Item = usize, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            if let Some(ref key) = new.idempotency_key {
+                if let Some(queued) = find_broadcast_by_idempotency_key(conn, key)? {
+                    return Ok(queued);
+                }
+            }
+
+            match conn.transaction(|| insert_broadcast(conn, &new)) {
+                // As for `enqueue`, a concurrent broadcast with the same key makes the insert
+                // fail, and we use that broadcast's count instead.
+                Err(e) => match new.idempotency_key {
+                    Some(ref key) if is_unique_violation(&e) => {
+                        find_broadcast_by_idempotency_key(conn, key)?.ok_or(e)
+                    }
+                    _ => Err(e),
+                },
+                r => r,
+            }
+        })
+    }
+
+    /// Cancels the sending of an email (by ID). This is only possible if it hasn't started sending
+    /// yet.
+    pub fn cancel(&self, id: u32) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            let target = mailer_queue::table
+                .filter(mailer_queue::id.eq(id))
+                .filter(mailer_queue::send_started.eq(false))
+                .filter(mailer_queue::send_done.eq(false))
+                .filter(mailer_queue::failed.eq(false));
+            let updated = diesel::update(target)
+                .set(mailer_queue::cancelled.eq(true))
+                .execute(conn)?;
+            if updated > 0 {
+                return Ok(());
+            }
+
+            let cancelled = mailer_queue::table
+                .filter(mailer_queue::id.eq(id))
+                .select(mailer_queue::cancelled)
+                .first::<bool>(conn)
+                .optional()?;
+            match cancelled {
+                Some(true) => Ok(()),
+                Some(false) => Err(ErrorKind::CannotCancel(id).into()),
+                None => Err(ErrorKind::NoSuchQueueItem(id).into()),
+            }
+        })
+    }
+
+    /// Checks that a template exists, given the names of it and its mailing list.
+    pub fn check_template_exists(
+        &self,
+        mailing_list: String,
+        template: String,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| find_template(conn, &mailing_list, &template).map(|_| ()))
+    }
+
+    /// Confirms the pending subscription with the given token, removing any earlier unsubscribe
+    /// from the mailing list. Returns the name of the mailing list and the subscriber's address,
+    /// or `None` if no subscription is pending with that token.
+    pub fn confirm_subscription(
+        &self,
+        token: String,
+    ) -> impl Future<Item = Option<(String, String)>, Error = Error> {
+        self.async_query(move |conn| {
+            conn.transaction(|| -> Result<_> {
+                let subscriber = mailer_subscribers::table
+                    .inner_join(mailer_lists::table)
+                    .filter(mailer_subscribers::confirm_token.eq(&token))
+                    .select((
+                        mailer_subscribers::id,
+                        mailer_subscribers::mailing_list_id,
+                        mailer_lists::name,
+                        mailer_subscribers::email,
+                    ))
+                    .first::<(u32, u32, String, String)>(conn)
+                    .optional()?;
+                let (id, mailing_list_id, name, email) = match subscriber {
+                    Some(subscriber) => subscriber,
+                    None => return Ok(None),
+                };
+
+                diesel::update(mailer_subscribers::table.filter(mailer_subscribers::id.eq(id)))
+                    .set((
+                        mailer_subscribers::confirmed.eq(true),
+                        mailer_subscribers::confirm_token.eq(None::<String>),
+                    ))
+                    .execute(conn)?;
+                let unsubscribes = mailer_unsubscribes::table
+                    .filter(mailer_unsubscribes::email.eq(&email))
+                    .filter(mailer_unsubscribes::mailing_list_id.eq(mailing_list_id));
+                diesel::delete(unsubscribes).execute(conn)?;
+                Ok(Some((name, email)))
+            })
+        })
+    }
+
+    /// Deletes the emails with the given IDs from the queue, returning how many were deleted.
+    pub fn delete_emails(&self, ids: Vec<u32>) -> impl Future<Item = usize, Error = Error> {
+        self.async_query(move |conn| {
+            diesel::delete(mailer_queue::table.filter(mailer_queue::id.eq_any(&ids))).execute(conn)
+        })
+    }
+
+    /// Deletes the failed emails matching the filter from the queue, returning how many were
+    /// deleted.
+    pub fn discard_failed(&self, filter: FailedFilter) -> impl Future<Item = usize, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            conn.transaction(|| {
+                let ids = find_failed(conn, &filter)?;
+                diesel::delete(mailer_queue::table.filter(mailer_queue::id.eq_any(ids)))
+                    .execute(conn)
+                    .map_err(Error::from)
+            })
+        })
+    }
+
+    /// Adds a mail to the queue, returning its ID. The mailing list and template are looked up by
+    /// name.
+    ///
+    /// If the mail has an idempotency key, and a mail with the same key was already queued, the
+    /// ID of that mail is returned instead, and nothing is added.
+    pub fn enqueue(&self, new: NewEmail) -> impl Future<Item = u32, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            if let Some(ref key) = new.idempotency_key {
+                if let Some(id) = find_by_idempotency_key(conn, key)? {
+                    return Ok(id);
+                }
+            }
+
+            let insert = || {
+                let (_, template_id) = find_template(conn, &new.mailing_list, &new.template)?;
+                insert_email(conn, template_id, &new, false)
+            };
+            match conn.transaction(insert) {
+                // If another request with the same key inserted its mail between our check and our
+                // insert, the insert fails, and we use that request's mail instead.
+                Err(e) => match new.idempotency_key {
+                    Some(ref key) if is_unique_violation(&e) => {
+                        find_by_idempotency_key(conn, key)?.ok_or(e)
+                    }
+                    _ => Err(e),
+                },
+                r => r,
+            }
+        })
+    }
+
+    /// Adds an email to the queue for each recipient, all in one transaction. Returns the ID of
+    /// each recipient's email, in the same order as the recipients, or `None` for recipients who
+    /// have unsubscribed from the mailing list, who are skipped.
+    pub fn enqueue_bulk(
+        &self,
+        new: NewBulkEmail,
+    ) -> impl Future<Item = Vec<Option<u32>>, Error = Error> {
+        self.async_query(move |conn| {
+            conn.transaction(|| {
+                let (mailing_list_id, template_id) =
+                    find_template(conn, &new.mailing_list, &new.template)?;
+                let emails = new
+                    .recipients
+                    .iter()
+                    .map(|recipient| &recipient.email)
+                    .collect::<Vec<_>>();
+                let unsubscribed = mailer_unsubscribes::table
+                    .filter(
+                        mailer_unsubscribes::mailing_list_id
+                            .eq(mailing_list_id)
+                            .or(mailer_unsubscribes::mailing_list_id.is_null()),
+                    )
+                    .filter(mailer_unsubscribes::email.eq_any(emails))
+                    .select(mailer_unsubscribes::email)
+                    .load::<String>(conn)?
+                    .into_iter()
+                    .map(|email| email.to_lowercase())
+                    .collect::<HashSet<_>>();
+
+                // MySQL compares addresses case-insensitively, so the set has to as well.
+                new.recipients
+                    .iter()
+                    .map(|recipient| {
+                        if unsubscribed.contains(&recipient.email.to_lowercase()) {
+                            return Ok(None);
+                        }
+                        insert_email(
+                            conn,
+                            template_id,
+                            &NewEmail {
+                                mailing_list: new.mailing_list.clone(),
+                                template: new.template.clone(),
+                                email: recipient.email.clone(),
+                                subject: new.subject.clone(),
+                                data: recipient.data.clone(),
+                                send_at: new.send_at,
+                                priority: new.priority,
+                                idempotency_key: None,
+                            },
+                        ).map(Some)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+        })
+    }
+
+    /// Deletes the subscriptions that are still unconfirmed, and were made before the given time.
+    /// Returns how many were deleted.
+    pub fn expire_subscriptions(
+        &self,
+        before: NaiveDateTime,
+    ) -> impl Future<Item = usize, Error = Error> {
+        self.async_query(move |conn| {
+            let target = mailer_subscribers::table
+                .filter(mailer_subscribers::confirmed.eq(false))
+                .filter(mailer_subscribers::created_at.lt(before));
+            diesel::delete(target).execute(conn)
+        })
     }
 
     /// Gets a mailing list's name from its ID.
@@ -53,30 +630,105 @@ impl DB {
         })
     }
 
-    /// Gets the next mail item to be sent. The fields are, in order,
-    /// `(id, mailing_list_id, template_id, to_addr, subject, data)`.
-    pub fn get_next_to_send(
+    /// Gets up to `limit` mail items to be sent, marking them as started. The items are claimed by
+    /// the given worker, whose ID must be unique to its process, until the lease runs out. Items
+    /// are taken highest priority first, then oldest first, and are returned in that order. An
+    /// empty batch means there's nothing left to send.
+    ///
+    /// This is safe to call from several instances of the mailer at once; the claim only succeeds
+    /// for items that are still unstarted when they're marked, so if another instance got to some
+    /// of them first, the batch is just smaller.
+    pub fn get_next_batch_to_send(
         &self,
-    ) -> impl Future<Item = Option<(u32, u32, u32, String, String, String)>, Error = Error> {
-        self.async_query(move |conn| {
+        worker_id: String,
+        lease: StdDuration,
+        limit: i64,
+    ) -> impl Future<Item = Vec<QueuedEmail>, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
             conn.transaction(|| {
-                let o = mailer_queue::table
+                let now = Utc::now().naive_utc();
+                // Confirmation emails are only held back by an opt-out from all mail, since they're
+                // sent when the address asks to be subscribed.
+                let candidates = mailer_queue::table
                     .inner_join(mailer_templates::table)
-                    .inner_join(
-                        mailer_lists::table
-                            .on(mailer_templates::mailing_list_id.eq(mailer_lists::id)),
-                    )
                     .left_join(
-                        mailer_unsubscribes::table
-                            .on(mailer_queue::email.eq(mailer_unsubscribes::email)),
+                        mailer_unsubscribes::table.on(mailer_queue::email
+                            .eq(mailer_unsubscribes::email)
+                            .and(
+                                mailer_unsubscribes::mailing_list_id
+                                    .eq(mailer_templates::mailing_list_id.nullable())
+                                    .and(mailer_queue::confirmation.eq(false))
+                                    .or(mailer_unsubscribes::mailing_list_id.is_null()),
+                            )),
                     )
+                    .filter(mailer_unsubscribes::id.is_null())
+                    .filter(mailer_queue::send_started.eq(false))
+                    .filter(mailer_queue::failed.eq(false))
+                    .filter(mailer_queue::cancelled.eq(false))
                     .filter(
-                        diesel::dsl::not(
-                            mailer_unsubscribes::email
-                                .is_not_null()
-                                .and(mailer_unsubscribes::mailing_list_id.eq(mailer_lists::id)),
-                        ).and(mailer_queue::send_started.eq(false)),
+                        mailer_queue::next_attempt
+                            .is_null()
+                            .or(mailer_queue::next_attempt.le(now)),
                     )
+                    .filter(
+                        mailer_queue::send_at
+                            .is_null()
+                            .or(mailer_queue::send_at.le(now)),
+                    )
+                    .order((mailer_queue::priority.desc(), mailer_queue::id.asc()))
+                    .limit(limit)
+                    .select(mailer_queue::id)
+                    .load::<u32>(conn)?;
+                if candidates.is_empty() {
+                    return Ok(Vec::new());
+                }
+
+                // The candidates were selected without locking them, so since then another
+                // instance may have claimed some of them, and some may have been cancelled or
+                // rescheduled. The update only takes the ones that are still unstarted and due.
+                // Any candidate that's claimed by this worker afterwards was claimed just now,
+                // since worker IDs are unique to a process, the sweeps of one process never
+                // overlap, and the emails it claimed earlier have all been released.
+                let lease_expires = later_by(now, lease);
+                let target = mailer_queue::table
+                    .filter(mailer_queue::id.eq_any(&candidates))
+                    .filter(mailer_queue::send_started.eq(false))
+                    .filter(mailer_queue::failed.eq(false))
+                    .filter(mailer_queue::cancelled.eq(false))
+                    .filter(
+                        mailer_queue::next_attempt
+                            .is_null()
+                            .or(mailer_queue::next_attempt.le(now)),
+                    )
+                    .filter(
+                        mailer_queue::send_at
+                            .is_null()
+                            .or(mailer_queue::send_at.le(now)),
+                    );
+                diesel::update(target)
+                    .set((
+                        mailer_queue::send_started.eq(true),
+                        mailer_queue::claimed_by.eq(&worker_id),
+                        mailer_queue::lease_expires.eq(lease_expires),
+                    ))
+                    .execute(conn)?;
+
+                mailer_queue::table
+                    .inner_join(mailer_templates::table)
+                    .left_join(
+                        mailer_subscribers::table.on(mailer_queue::email
+                            .eq(mailer_subscribers::email)
+                            .and(
+                                mailer_subscribers::mailing_list_id
+                                    .eq(mailer_templates::mailing_list_id),
+                            )),
+                    )
+                    .filter(mailer_queue::id.eq_any(&candidates))
+                    .filter(mailer_queue::send_started.eq(true))
+                    .filter(mailer_queue::send_done.eq(false))
+                    .filter(mailer_queue::cancelled.eq(false))
+                    .filter(mailer_queue::claimed_by.eq(&worker_id))
+                    .order((mailer_queue::priority.desc(), mailer_queue::id.asc()))
                     .select((
                         mailer_queue::id,
                         mailer_templates::mailing_list_id,
@@ -84,23 +736,103 @@ impl DB {
                         mailer_queue::email,
                         mailer_queue::subject,
                         mailer_queue::data,
+                        mailer_subscribers::attributes.nullable(),
                     ))
-                    .first(conn)
-                    .optional()?;
+                    .load::<QueuedEmail>(conn)
+                    .map_err(Error::from)
+            })
+        })
+    }
 
-                if let Some(data) = o {
-                    let (id, _, _, _, _, _) = data;
-                    diesel::update(mailer_queue::table.filter(mailer_queue::id.eq(id)))
-                        .set(mailer_queue::send_started.eq(true))
-                        .execute(conn)
-                        .map(|_| Some(data))
-                } else {
-                    Ok(None)
-                }
+    /// Returns the mailing preferences of an address: every mailing list, with whether the address
+    /// still receives mail from it, and whether the address has opted out of all mail.
+    pub fn get_preferences(
+        &self,
+        email: String,
+    ) -> impl Future<Item = (Vec<(u32, String, bool)>, bool), Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            let unsubscribed = find_unsubscribes(conn, &email)?;
+            let lists = mailer_lists::table
+                .order(mailer_lists::name.asc())
+                .select((mailer_lists::id, mailer_lists::name))
+                .load::<(u32, String)>(conn)?
+                .into_iter()
+                .map(|(id, name)| (id, name, !unsubscribed.contains(&Some(id))))
+                .collect();
+            Ok((lists, unsubscribed.contains(&None)))
+        })
+    }
+
+    /// Gets the status of an email (by ID).
+    pub fn get_queue_status(&self, id: u32) -> impl Future<Item = QueueStatus, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            let (
+                send_started,
+                send_done,
+                failed,
+                cancelled,
+                attempts,
+                last_error,
+                email,
+                list_id,
+                confirmation,
+            ) = mailer_queue::table
+                .inner_join(mailer_templates::table)
+                .filter(mailer_queue::id.eq(id))
+                .select((
+                    mailer_queue::send_started,
+                    mailer_queue::send_done,
+                    mailer_queue::failed,
+                    mailer_queue::cancelled,
+                    mailer_queue::attempts,
+                    mailer_queue::last_error,
+                    mailer_queue::email,
+                    mailer_templates::mailing_list_id,
+                    mailer_queue::confirmation,
+                ))
+                .first::<(bool, bool, bool, bool, u32, Option<String>, String, u32, bool)>(conn)
+                .optional()?
+                .ok_or_else(|| ErrorKind::NoSuchQueueItem(id))?;
+
+            let state = if cancelled {
+                QueueState::Cancelled
+            } else if send_done {
+                QueueState::Sent
+            } else if failed {
+                QueueState::Failed
+            } else if send_started {
+                QueueState::Sending
+            } else if is_unsubscribed(
+                conn,
+                &email,
+                // Confirmation emails are only suppressed by an opt-out from all mail.
+                Some(list_id).filter(|_| !confirmation),
+            )? {
+                QueueState::Suppressed
+            } else {
+                QueueState::Pending
+            };
+            Ok(QueueStatus {
+                state,
+                attempts,
+                last_error,
             })
         })
     }
 
+    /// Gets the subscriber to the mailing list with the given ID who has the given address.
+    pub fn get_subscriber(
+        &self,
+        mailing_list_id: u32,
+        email: String,
+    ) -> impl Future<Item = Subscriber, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            check_mailing_list_exists(conn, mailing_list_id)?;
+            find_subscriber(conn, mailing_list_id, &email)?
+                .ok_or_else(|| ErrorKind::NoSuchSubscriber(email.clone()).into())
+        })
+    }
+
     /// Gets the raw text of a template.
     fn get_template(
         &self,
@@ -123,6 +855,35 @@ impl DB {
         })
     }
 
+    /// Returns the failed emails matching the filter, oldest first.
+    pub fn list_failed(
+        &self,
+        filter: FailedFilter,
+    ) -> impl Future<Item = Vec<FailedEmail>, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            let ids = find_failed(conn, &filter)?;
+            mailer_queue::table
+                .inner_join(mailer_templates::table)
+                .inner_join(
+                    mailer_lists::table.on(mailer_templates::mailing_list_id.eq(mailer_lists::id)),
+                )
+                .filter(mailer_queue::id.eq_any(ids))
+                .order(mailer_queue::id.asc())
+                .select((
+                    mailer_queue::id,
+                    mailer_lists::name,
+                    mailer_templates::name,
+                    mailer_queue::email,
+                    mailer_queue::subject,
+                    mailer_queue::created_at,
+                    mailer_queue::attempts,
+                    mailer_queue::last_error,
+                ))
+                .load::<FailedEmail>(conn)
+                .map_err(Error::from)
+        })
+    }
+
     /// Returns a list of mailing lists.
     pub fn list_mailing_lists(&self) -> impl Future<Item = Vec<(u32, String)>, Error = Error> {
         self.async_query(move |conn| {
@@ -132,6 +893,102 @@ impl DB {
         })
     }
 
+    /// Returns the emails that are scheduled to be sent in the future, soonest first.
+    pub fn list_scheduled(&self) -> impl Future<Item = Vec<ScheduledEmail>, Error = Error> {
+        self.async_query(move |conn| {
+            mailer_queue::table
+                .inner_join(mailer_templates::table)
+                .inner_join(
+                    mailer_lists::table.on(mailer_templates::mailing_list_id.eq(mailer_lists::id)),
+                )
+                .filter(mailer_queue::send_at.gt(Utc::now().naive_utc()))
+                .filter(mailer_queue::send_started.eq(false))
+                .filter(mailer_queue::failed.eq(false))
+                .filter(mailer_queue::cancelled.eq(false))
+                .order(mailer_queue::send_at.asc())
+                .select((
+                    mailer_queue::id,
+                    mailer_lists::name,
+                    mailer_templates::name,
+                    mailer_queue::email,
+                    mailer_queue::subject,
+                    mailer_queue::send_at,
+                ))
+                .load::<(u32, String, String, String, String, Option<NaiveDateTime>)>(conn)
+                .map(|rows| {
+                    rows.into_iter()
+                        .filter_map(|(id, mailing_list, template, to_addr, subject, send_at)| {
+                            send_at.map(|send_at| ScheduledEmail {
+                                id,
+                                mailing_list,
+                                template,
+                                to_addr,
+                                subject,
+                                send_at,
+                            })
+                        })
+                        .collect()
+                })
+        })
+    }
+
+    /// Returns up to `limit` emails that were sent before the given time, oldest first.
+    pub fn list_sent_before(
+        &self,
+        before: NaiveDateTime,
+        limit: i64,
+    ) -> impl Future<Item = Vec<SentEmail>, Error = Error> {
+        self.async_query(move |conn| {
+            mailer_queue::table
+                .inner_join(mailer_templates::table)
+                .inner_join(
+                    mailer_lists::table.on(mailer_templates::mailing_list_id.eq(mailer_lists::id)),
+                )
+                .filter(mailer_queue::send_done.eq(true))
+                .filter(mailer_queue::sent_at.lt(before))
+                .order(mailer_queue::sent_at.asc())
+                .limit(limit)
+                .select((
+                    mailer_queue::id,
+                    mailer_lists::name,
+                    mailer_templates::name,
+                    mailer_queue::email,
+                    mailer_queue::subject,
+                    mailer_queue::data,
+                    mailer_queue::created_at,
+                    mailer_queue::sent_at,
+                ))
+                .load::<SentEmail>(conn)
+        })
+    }
+
+    /// Returns up to `limit` subscribers to the mailing list with the given ID, in the order they
+    /// were added, starting after the subscriber with the ID `after`.
+    pub fn list_subscribers(
+        &self,
+        mailing_list_id: u32,
+        after: Option<u32>,
+        limit: i64,
+    ) -> impl Future<Item = Vec<Subscriber>, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            check_mailing_list_exists(conn, mailing_list_id)?;
+            let subscribers = mailer_subscribers::table
+                .filter(mailer_subscribers::mailing_list_id.eq(mailing_list_id))
+                .filter(mailer_subscribers::id.gt(after.unwrap_or(0)))
+                .order(mailer_subscribers::id.asc())
+                .limit(limit)
+                .select((
+                    mailer_subscribers::id,
+                    mailer_subscribers::email,
+                    mailer_subscribers::attributes,
+                    mailer_subscribers::created_at,
+                    mailer_subscribers::confirmed,
+                ))
+                .load(conn)?;
+            Ok(subscribers)
+        })
+    }
+
     /// Returns a list of template names for the given mailing list.
     pub fn list_templates(
         &self,
@@ -145,41 +1002,58 @@ impl DB {
         })
     }
 
-    /// Loads a template recursively, returning a Tera instance with the required mailer_templates, as
-    /// well as the template's name.
+    /// Loads a template recursively, returning a function that renders it. The templates of each
+    /// mailing list are compiled once, and compiled again only when one of them changes.
     pub fn load_template(
         &self,
         id: u32,
     ) -> impl Future<Item = impl Fn(Context) -> Result<String>, Error = Error> {
-        // This can be made a lot more efficient when https://github.com/Keats/tera/issues/322 is
-        // resolved. There also may be a more efficient way to write the query (to do one instead
-        // of two), but that's probably small potatoes.
+        let templates = self.templates.clone();
         self.async_query(move |conn| -> Result<_> {
-            let (mailing_list_id, name) = mailer_templates::table
+            let (mailing_list_id, name, version) = mailer_templates::table
+                .inner_join(mailer_lists::table)
                 .filter(mailer_templates::id.eq(id))
-                .select((mailer_templates::mailing_list_id, mailer_templates::name))
-                .first::<(u32, String)>(conn)?;
-            let mailer_templates = mailer_templates::table
-                .filter(mailer_templates::mailing_list_id.eq(mailing_list_id))
                 .select((
+                    mailer_templates::mailing_list_id,
                     mailer_templates::name,
-                    mailer_templates::contents,
-                    mailer_templates::markdown,
+                    mailer_lists::templates_version,
                 ))
-                .load::<(String, String, bool)>(conn)?;
-
-            let mut tera = Tera::default();
-            for (name, contents, markdown) in mailer_templates {
-                let contents = if markdown {
-                    let mut html = String::new();
-                    push_html(&mut html, pulldown_cmark::Parser::new(&contents));
-                    html
-                } else {
-                    contents
-                };
-                tera.add_raw_template(&name, &contents)?;
-            }
-            tera.build_inheritance_chains()?;
+                .first::<(u32, String, u32)>(conn)?;
+
+            let cached = templates
+                .read()
+                .unwrap()
+                .get(&mailing_list_id)
+                .and_then(|&(cached_version, ref tera)| {
+                    if cached_version == version {
+                        Some(tera.clone())
+                    } else {
+                        None
+                    }
+                });
+            let tera = match cached {
+                Some(tera) => {
+                    metrics::TEMPLATE_CACHE_HITS.add(1);
+                    tera
+                }
+                None => {
+                    metrics::TEMPLATE_CACHE_MISSES.add(1);
+
+                    // The templates are loaded after the version is, so they're at least as new
+                    // as it. At worst, newer templates are cached under an older version, and
+                    // compiled again on the next load.
+                    let tera = Arc::new(compile_templates(conn, mailing_list_id)?);
+                    let mut templates = templates.write().unwrap();
+                    let newer_cached = templates
+                        .get(&mailing_list_id)
+                        .map(|&(cached_version, _)| cached_version > version)
+                        .unwrap_or(false);
+                    if !newer_cached {
+                        templates.insert(mailing_list_id, (version, tera.clone()));
+                    }
+                    tera
+                }
+            };
 
             Ok(move |data| tera.render(&name, &data).map_err(Error::from))
         })
@@ -224,17 +1098,256 @@ impl DB {
         })
     }
 
-    /// Marks the sending of an email (by ID) as finished.
-    pub fn set_email_done(&self, id: u32) -> impl Future<Item = (), Error = Error> {
+    /// Deletes the data of emails that were sent before the given time, returning how many emails
+    /// were changed.
+    pub fn purge_sent_data(
+        &self,
+        before: NaiveDateTime,
+    ) -> impl Future<Item = usize, Error = Error> {
         self.async_query(move |conn| {
-            diesel::update(mailer_queue::table.filter(mailer_queue::id.eq(id)))
+            let target = mailer_queue::table
+                .filter(mailer_queue::send_done.eq(true))
+                .filter(mailer_queue::sent_at.lt(before))
+                .filter(mailer_queue::data.ne(""));
+            diesel::update(target)
+                .set(mailer_queue::data.eq(""))
+                .execute(conn)
+        })
+    }
+
+    /// Releases the claims on emails whose leases have expired without them being sent, so that
+    /// they get sent again. Returns the number of emails reclaimed.
+    pub fn reclaim_expired_leases(&self) -> impl Future<Item = usize, Error = Error> {
+        self.async_query(move |conn| {
+            let target = mailer_queue::table
                 .filter(mailer_queue::send_started.eq(true))
-                .set(mailer_queue::send_done.eq(true))
+                .filter(mailer_queue::send_done.eq(false))
+                .filter(mailer_queue::lease_expires.lt(Utc::now().naive_utc()));
+            diesel::update(target)
+                .set((
+                    mailer_queue::send_started.eq(false),
+                    mailer_queue::claimed_by.eq(None::<String>),
+                    mailer_queue::lease_expires.eq(None::<NaiveDateTime>),
+                ))
                 .execute(conn)
+        })
+    }
+
+    /// Removes the subscriber with the given address from the mailing list with the given ID.
+    pub fn remove_subscriber(
+        &self,
+        mailing_list_id: u32,
+        email: String,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            check_mailing_list_exists(conn, mailing_list_id)?;
+            let target = mailer_subscribers::table
+                .filter(mailer_subscribers::mailing_list_id.eq(mailing_list_id))
+                .filter(mailer_subscribers::email.eq(&email));
+            match diesel::delete(target).execute(conn)? {
+                0 => Err(ErrorKind::NoSuchSubscriber(email.clone()).into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    /// Puts the failed emails matching the filter back in the queue, with their attempts reset.
+    /// Returns how many were requeued.
+    pub fn requeue_failed(&self, filter: FailedFilter) -> impl Future<Item = usize, Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            conn.transaction(|| {
+                let ids = find_failed(conn, &filter)?;
+                diesel::update(mailer_queue::table.filter(mailer_queue::id.eq_any(ids)))
+                    .set((
+                        mailer_queue::failed.eq(false),
+                        mailer_queue::attempts.eq(0),
+                        mailer_queue::last_error.eq(None::<String>),
+                        mailer_queue::next_attempt.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(conn)
+                    .map_err(Error::from)
+            })
+        })
+    }
+
+    /// Changes the time an email (by ID) is scheduled to be sent at. Only emails that haven't
+    /// started sending can be rescheduled.
+    pub fn reschedule(
+        &self,
+        id: u32,
+        send_at: NaiveDateTime,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            let target = mailer_queue::table
+                .filter(mailer_queue::id.eq(id))
+                .filter(mailer_queue::send_started.eq(false))
+                .filter(mailer_queue::send_done.eq(false))
+                .filter(mailer_queue::failed.eq(false))
+                .filter(mailer_queue::cancelled.eq(false));
+            let updated = diesel::update(target)
+                .set(mailer_queue::send_at.eq(send_at))
+                .execute(conn)?;
+            if updated == 0 {
+                Err(ErrorKind::NoSuchQueueItem(id).into())
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Removes an address's unsubscribe from the given mailing list, so it's sent mail on the list
+    /// again. An opt-out from all mail is left alone.
+    pub fn resubscribe(
+        &self,
+        email: String,
+        mailing_list_id: u32,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| {
+            diesel::delete(
+                mailer_unsubscribes::table
+                    .filter(mailer_unsubscribes::email.eq(&email))
+                    .filter(mailer_unsubscribes::mailing_list_id.eq(mailing_list_id)),
+            ).execute(conn)
                 .map(|_| ())
         })
     }
 
+    /// Marks the sending of an email (by ID) as finished. Nothing is changed unless the email is
+    /// still claimed by the given worker; if its lease expired and another worker reclaimed it, the
+    /// email is left to that worker.
+    pub fn set_email_done(
+        &self,
+        id: u32,
+        worker_id: String,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| {
+            let target = mailer_queue::table
+                .filter(mailer_queue::id.eq(id))
+                .filter(mailer_queue::send_started.eq(true))
+                .filter(mailer_queue::claimed_by.eq(&worker_id));
+            diesel::update(target)
+                .set((
+                    mailer_queue::send_done.eq(true),
+                    mailer_queue::sent_at.eq(Utc::now().naive_utc()),
+                    mailer_queue::lease_expires.eq(None::<NaiveDateTime>),
+                ))
+                .execute(conn)
+                .map(|updated| {
+                    if updated == 0 {
+                        warn!("Email {} was sent after its lease expired.", id);
+                    }
+                })
+        })
+    }
+
+    /// Records a failed attempt to send an email (by ID), with the error that caused it. If the
+    /// email has now been attempted as many times as the retry policy allows, it's marked as
+    /// permanently failed; otherwise, it's scheduled to be retried after a delay. Returns whether
+    /// the failure was permanent. As with `set_email_done`, nothing is changed unless the email is
+    /// still claimed by the given worker.
+    pub fn set_email_failed(
+        &self,
+        id: u32,
+        worker_id: String,
+        error: String,
+        retry: RetryPolicy,
+    ) -> impl Future<Item = bool, Error = Error> {
+        self.async_query(move |conn| {
+            conn.transaction::<_, Error, _>(|| {
+                let target = || {
+                    mailer_queue::table
+                        .filter(mailer_queue::id.eq(id))
+                        .filter(mailer_queue::send_started.eq(true))
+                        .filter(mailer_queue::claimed_by.eq(&worker_id))
+                };
+                let attempts = match target()
+                    .select(mailer_queue::attempts)
+                    .first::<u32>(conn)
+                    .optional()?
+                {
+                    Some(attempts) => attempts + 1,
+                    None => {
+                        warn!("Email {} failed to send after its lease expired.", id);
+                        return Ok(false);
+                    }
+                };
+                let failed = attempts >= retry.max_attempts;
+                let next_attempt = if failed {
+                    None
+                } else {
+                    Some(later_by(Utc::now().naive_utc(), retry.delay(attempts)))
+                };
+
+                diesel::update(target())
+                    .set((
+                        mailer_queue::attempts.eq(attempts),
+                        mailer_queue::last_error.eq(&error),
+                        mailer_queue::next_attempt.eq(next_attempt),
+                        mailer_queue::failed.eq(failed),
+                        mailer_queue::send_started.eq(false),
+                        mailer_queue::claimed_by.eq(None::<String>),
+                        mailer_queue::lease_expires.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(conn)?;
+                Ok(failed)
+            })
+        })
+    }
+
+    /// Sets the mailing preferences of an address: of the mailing lists in `shown`, it's
+    /// unsubscribed from every one but those in `subscribed`, and it's unsubscribed from all mail
+    /// if `unsubscribe_all` is set. Lists that weren't shown, such as ones created after the
+    /// preferences page was loaded, are left as they were, as are existing unsubscribes that still
+    /// apply.
+    pub fn set_preferences(
+        &self,
+        email: String,
+        shown: Vec<u32>,
+        subscribed: Vec<u32>,
+        unsubscribe_all: bool,
+    ) -> impl Future<Item = (), Error = Error> {
+        self.async_query(move |conn| -> Result<_> {
+            conn.transaction(|| {
+                let unsubscribed = find_unsubscribes(conn, &email)?;
+                let mut wanted = mailer_lists::table
+                    .filter(mailer_lists::id.eq_any(&shown))
+                    .select(mailer_lists::id)
+                    .load::<u32>(conn)?
+                    .into_iter()
+                    .filter(|id| !subscribed.contains(id))
+                    .map(Some)
+                    .collect::<HashSet<_>>();
+                if unsubscribe_all {
+                    wanted.insert(None);
+                }
+
+                let resubscribed = unsubscribed
+                    .iter()
+                    .filter_map(|&id| id)
+                    .filter(|id| shown.contains(id))
+                    .filter(|&id| !wanted.contains(&Some(id)))
+                    .collect::<Vec<_>>();
+                diesel::delete(
+                    mailer_unsubscribes::table
+                        .filter(mailer_unsubscribes::email.eq(&email))
+                        .filter(mailer_unsubscribes::mailing_list_id.eq_any(resubscribed)),
+                ).execute(conn)?;
+                if !unsubscribe_all {
+                    diesel::delete(
+                        mailer_unsubscribes::table
+                            .filter(mailer_unsubscribes::email.eq(&email))
+                            .filter(mailer_unsubscribes::mailing_list_id.is_null()),
+                    ).execute(conn)?;
+                }
+
+                for &id in wanted.difference(&unsubscribed) {
+                    insert_unsubscribe(conn, &email, id, UnsubscribeSource::Link)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
     /// Sets the contents of the template with the given name.
     pub fn set_template(
         &self,
@@ -260,20 +1373,120 @@ impl DB {
         })
     }
 
-    /// Marks a user as having unsubscribed from the given mailing list.
+    /// Records a pending subscription to the mailing list with the given ID, and queues an email
+    /// asking the subscriber to confirm it, using the list's confirmation template. The template
+    /// is given the confirmation URL, the address, and the list's name as data. Returns the name
+    /// of the list, and the ID of the queued email, or `None` if the address is already a
+    /// confirmed subscriber who hasn't unsubscribed from the list, in which case nothing is
+    /// queued.
+    ///
+    /// If a subscription is already pending for the address, its token is replaced, and its
+    /// expiry starts over. A confirmed subscriber who unsubscribed from the list is sent a
+    /// confirmation too, and confirming it removes the unsubscribe.
+    pub fn subscribe(
+        &self,
+        mailing_list_id: u32,
+        email: String,
+        token: String,
+        confirm_url: String,
+    ) -> impl Future<Item = (String, Option<u32>), Error = Error> {
+        self.async_query(move |conn| {
+            conn.transaction(|| -> Result<_> {
+                let (name, confirm_template_id) = mailer_lists::table
+                    .filter(mailer_lists::id.eq(mailing_list_id))
+                    .select((mailer_lists::name, mailer_lists::confirm_template_id))
+                    .first::<(String, Option<u32>)>(conn)
+                    .optional()?
+                    .ok_or_else(|| ErrorKind::NoSuchMailingListId(mailing_list_id))?;
+                let template_id = confirm_template_id
+                    .ok_or_else(|| ErrorKind::NoConfirmTemplate(mailing_list_id))?;
+
+                // The expiry of pending subscriptions is checked against the mailer's clock, so
+                // that's the clock they're created with, rather than the database's.
+                let now = Utc::now().naive_utc();
+                let target = mailer_subscribers::table
+                    .filter(mailer_subscribers::mailing_list_id.eq(mailing_list_id))
+                    .filter(mailer_subscribers::email.eq(&email));
+                let subscriber = find_subscriber(conn, mailing_list_id, &email)?;
+                match subscriber.map(|subscriber| subscriber.confirmed) {
+                    Some(true) => {
+                        // A confirmed subscriber who unsubscribed from the list can get back on
+                        // it by confirming again. They stay confirmed in the meantime, so their
+                        // attributes aren't expired along with pending subscriptions.
+                        let unsubscribed = diesel::select(diesel::dsl::exists(
+                            mailer_unsubscribes::table
+                                .filter(mailer_unsubscribes::email.eq(&email))
+                                .filter(mailer_unsubscribes::mailing_list_id.eq(mailing_list_id)),
+                        )).get_result::<bool>(conn)?;
+                        if !unsubscribed {
+                            return Ok((name, None));
+                        }
+                        diesel::update(target)
+                            .set(mailer_subscribers::confirm_token.eq(&token))
+                            .execute(conn)?;
+                    }
+                    Some(false) => {
+                        diesel::update(target)
+                            .set((
+                                mailer_subscribers::confirm_token.eq(&token),
+                                mailer_subscribers::created_at.eq(now),
+                            ))
+                            .execute(conn)?;
+                    }
+                    None => {
+                        diesel::insert_into(mailer_subscribers::table)
+                            .values((
+                                mailer_subscribers::mailing_list_id.eq(mailing_list_id),
+                                mailer_subscribers::email.eq(&email),
+                                mailer_subscribers::created_at.eq(now),
+                                mailer_subscribers::attributes.eq("{}"),
+                                mailer_subscribers::confirmed.eq(false),
+                                mailer_subscribers::confirm_token.eq(&token),
+                            ))
+                            .execute(conn)?;
+                    }
+                }
+
+                let template = mailer_templates::table
+                    .filter(mailer_templates::id.eq(template_id))
+                    .select(mailer_templates::name)
+                    .first::<String>(conn)?;
+                let data = json!({
+                    "confirm": confirm_url,
+                    "email": email,
+                    "mailing_list": name,
+                });
+                let id = insert_email(
+                    conn,
+                    template_id,
+                    &NewEmail {
+                        mailing_list: name.clone(),
+                        subject: format!("Confirm your subscription to {}", name),
+                        template,
+                        email: email.clone(),
+                        data: data.to_string(),
+                        send_at: None,
+                        priority: CONFIRMATION_PRIORITY,
+                        idempotency_key: None,
+                    },
+                    true,
+                )?;
+                Ok((name, Some(id)))
+            })
+        })
+    }
+
+    /// Marks an address as having unsubscribed from the given mailing list, or from all mailing
+    /// lists if none is given. Returns `false` if it had already unsubscribed, in which case the
+    /// existing unsubscribe is left as it was.
     pub fn unsubscribe(
         &self,
         email: String,
-        mailing_list_id: u32,
-    ) -> impl Future<Item = (), Error = Error> {
+        mailing_list_id: Option<u32>,
+        source: UnsubscribeSource,
+    ) -> impl Future<Item = bool, Error = Error> {
         self.async_query(move |conn| {
-            diesel::insert_into(mailer_unsubscribes::table)
-                .values((
-                    mailer_unsubscribes::email.eq(&email),
-                    mailer_unsubscribes::mailing_list_id.eq(mailing_list_id),
-                ))
-                .execute(conn)
-                .map(|_| ())
+            conn.transaction(|| insert_unsubscribe(conn, &email, mailing_list_id, source))
         })
     }
 
@@ -294,3 +1507,297 @@ impl DB {
         }
     }
 }
+
+/// Checks that the mailing list with the given ID exists.
+fn check_mailing_list_exists(conn: &MysqlConnection, mailing_list_id: u32) -> Result<()> {
+    let exists = diesel::select(diesel::dsl::exists(
+        mailer_lists::table.filter(mailer_lists::id.eq(mailing_list_id)),
+    )).get_result(conn)?;
+    if exists {
+        Ok(())
+    } else {
+        Err(ErrorKind::NoSuchMailingListId(mailing_list_id).into())
+    }
+}
+
+/// Compiles every template in a mailing list.
+fn compile_templates(conn: &MysqlConnection, mailing_list_id: u32) -> Result<Tera> {
+    // This can be made a lot more efficient when https://github.com/Keats/tera/issues/322 is
+    // resolved.
+    let templates = mailer_templates::table
+        .filter(mailer_templates::mailing_list_id.eq(mailing_list_id))
+        .select((
+            mailer_templates::name,
+            mailer_templates::contents,
+            mailer_templates::markdown,
+        ))
+        .load::<(String, String, bool)>(conn)?;
+
+    let mut tera = Tera::default();
+    for (name, contents, markdown) in templates {
+        let contents = if markdown {
+            let mut html = String::new();
+            push_html(&mut html, pulldown_cmark::Parser::new(&contents));
+            html
+        } else {
+            contents
+        };
+        tera.add_raw_template(&name, &contents)?;
+    }
+    tera.build_inheritance_chains()?;
+    Ok(tera)
+}
+
+/// Finds the number of emails queued by the broadcast with the given idempotency key, if there
+/// was one.
+fn find_broadcast_by_idempotency_key(conn: &MysqlConnection, key: &str) -> Result<Option<usize>> {
+    mailer_broadcasts::table
+        .filter(mailer_broadcasts::idempotency_key.eq(key))
+        .select(mailer_broadcasts::queued)
+        .first::<u32>(conn)
+        .optional()
+        .map(|queued| queued.map(|queued| queued as usize))
+        .map_err(Error::from)
+}
+
+/// Finds the ID of the mail in the queue with the given idempotency key, if there is one.
+fn find_by_idempotency_key(conn: &MysqlConnection, key: &str) -> Result<Option<u32>> {
+    mailer_queue::table
+        .filter(mailer_queue::idempotency_key.eq(key))
+        .select(mailer_queue::id)
+        .first(conn)
+        .optional()
+        .map_err(Error::from)
+}
+
+/// Returns the IDs of the failed emails matching the filter, oldest first.
+fn find_failed(conn: &MysqlConnection, filter: &FailedFilter) -> Result<Vec<u32>> {
+    let mut query = mailer_queue::table
+        .filter(mailer_queue::failed.eq(true))
+        .select(mailer_queue::id)
+        .order(mailer_queue::id.asc())
+        .into_boxed();
+
+    if filter.mailing_list.is_some() || filter.template.is_some() {
+        let mut templates = mailer_templates::table
+            .inner_join(mailer_lists::table)
+            .select(mailer_templates::id)
+            .into_boxed();
+        if let Some(ref name) = filter.mailing_list {
+            templates = templates.filter(mailer_lists::name.eq(name));
+        }
+        if let Some(ref name) = filter.template {
+            templates = templates.filter(mailer_templates::name.eq(name));
+        }
+        let template_ids = templates.load::<u32>(conn)?;
+        query = query.filter(mailer_queue::template_id.eq_any(template_ids));
+    }
+    if let Some(since) = filter.since {
+        query = query.filter(mailer_queue::created_at.ge(since));
+    }
+    if let Some(until) = filter.until {
+        query = query.filter(mailer_queue::created_at.le(until));
+    }
+    if let Some(ref error) = filter.error {
+        let escaped = error
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        query = query.filter(mailer_queue::last_error.like(format!("%{}%", escaped)));
+    }
+
+    query.load(conn).map_err(Error::from)
+}
+
+/// Finds the subscriber to the mailing list with the given ID who has the given address.
+fn find_subscriber(
+    conn: &MysqlConnection,
+    mailing_list_id: u32,
+    email: &str,
+) -> Result<Option<Subscriber>> {
+    mailer_subscribers::table
+        .filter(mailer_subscribers::mailing_list_id.eq(mailing_list_id))
+        .filter(mailer_subscribers::email.eq(email))
+        .select((
+            mailer_subscribers::id,
+            mailer_subscribers::email,
+            mailer_subscribers::attributes,
+            mailer_subscribers::created_at,
+            mailer_subscribers::confirmed,
+        ))
+        .first(conn)
+        .optional()
+        .map_err(Error::from)
+}
+
+/// Finds a template by the names of its mailing list and itself, returning the IDs of both.
+fn find_template(
+    conn: &MysqlConnection,
+    mailing_list: &str,
+    template: &str,
+) -> Result<(u32, u32)> {
+    let mailing_list_id = mailer_lists::table
+        .filter(mailer_lists::name.eq(mailing_list))
+        .select(mailer_lists::id)
+        .first::<u32>(conn)
+        .optional()?
+        .ok_or_else(|| ErrorKind::NoSuchMailingList(mailing_list.to_string()))?;
+    let template_id = mailer_templates::table
+        .filter(mailer_templates::mailing_list_id.eq(mailing_list_id))
+        .filter(mailer_templates::name.eq(template))
+        .select(mailer_templates::id)
+        .first::<u32>(conn)
+        .optional()?
+        .ok_or_else(|| ErrorKind::NoSuchTemplate(template.to_string()))?;
+    Ok((mailing_list_id, template_id))
+}
+
+/// Returns the IDs of the mailing lists the given address has unsubscribed from, including `None`
+/// if it has unsubscribed from all of them.
+fn find_unsubscribes(conn: &MysqlConnection, email: &str) -> Result<HashSet<Option<u32>>> {
+    mailer_unsubscribes::table
+        .filter(mailer_unsubscribes::email.eq(email))
+        .select(mailer_unsubscribes::mailing_list_id)
+        .load::<Option<u32>>(conn)
+        .map(|ids| ids.into_iter().collect())
+        .map_err(Error::from)
+}
+
+/// Queues a broadcast, and records it in `mailer_broadcasts`. Returns the number of emails
+/// queued. This should be called inside a transaction.
+fn insert_broadcast(conn: &MysqlConnection, new: &NewBroadcast) -> Result<usize> {
+    let (mailing_list_id, template_id) = find_template(conn, &new.mailing_list, &new.template)?;
+    let subscribers = mailer_subscribers::table
+        .left_join(
+            mailer_unsubscribes::table.on(mailer_subscribers::email
+                .eq(mailer_unsubscribes::email)
+                .and(
+                    mailer_unsubscribes::mailing_list_id
+                        .eq(mailer_subscribers::mailing_list_id.nullable())
+                        .or(mailer_unsubscribes::mailing_list_id.is_null()),
+                )),
+        )
+        .filter(mailer_subscribers::mailing_list_id.eq(mailing_list_id))
+        .filter(mailer_subscribers::confirmed.eq(true))
+        .filter(mailer_unsubscribes::id.is_null())
+        .order(mailer_subscribers::id.asc())
+        .select(mailer_subscribers::email)
+        .load::<String>(conn)?;
+
+    for email in &subscribers {
+        insert_email(
+            conn,
+            template_id,
+            &NewEmail {
+                mailing_list: new.mailing_list.clone(),
+                template: new.template.clone(),
+                email: email.clone(),
+                subject: new.subject.clone(),
+                data: new.data.clone(),
+                send_at: new.send_at,
+                priority: new.priority,
+                idempotency_key: None,
+            },
+            false,
+        )?;
+    }
+
+    diesel::insert_into(mailer_broadcasts::table)
+        .values((
+            mailer_broadcasts::mailing_list_id.eq(mailing_list_id),
+            mailer_broadcasts::template_id.eq(template_id),
+            mailer_broadcasts::idempotency_key.eq(&new.idempotency_key),
+            mailer_broadcasts::queued.eq(subscribers.len() as u32),
+        ))
+        .execute(conn)?;
+    Ok(subscribers.len())
+}
+
+/// Inserts a mail using the template with the given ID into the queue, returning its ID. A
+/// confirmation email is sent even if the address has unsubscribed from the mailing list, since
+/// it's only sent when the address asks to be subscribed. This should be called inside a
+/// transaction.
+fn insert_email(
+    conn: &MysqlConnection,
+    template_id: u32,
+    new: &NewEmail,
+    confirmation: bool,
+) -> Result<u32> {
+    diesel::insert_into(mailer_queue::table)
+        .values((
+            mailer_queue::template_id.eq(template_id),
+            mailer_queue::data.eq(&new.data),
+            mailer_queue::email.eq(&new.email),
+            mailer_queue::subject.eq(&new.subject),
+            mailer_queue::send_at.eq(new.send_at),
+            mailer_queue::priority.eq(new.priority),
+            mailer_queue::idempotency_key.eq(&new.idempotency_key),
+            mailer_queue::confirmation.eq(confirmation),
+        ))
+        .execute(conn)?;
+    let id = diesel::select(last_insert_id).first::<u64>(conn)?;
+    Ok(id as u32)
+}
+
+/// Adds an unsubscribe for the given address, unless it already has one. Returns whether one was
+/// added. This should be called inside a transaction.
+fn insert_unsubscribe(
+    conn: &MysqlConnection,
+    email: &str,
+    mailing_list_id: Option<u32>,
+    source: UnsubscribeSource,
+) -> Result<bool> {
+    // The unique index on the email and list (which counts opt-outs from all mail as list 0) keeps
+    // the address from having two unsubscribes, even if two requests add one at once.
+    let r = diesel::insert_into(mailer_unsubscribes::table)
+        .values((
+            mailer_unsubscribes::email.eq(email),
+            mailer_unsubscribes::mailing_list_id.eq(mailing_list_id),
+            mailer_unsubscribes::source.eq(source.as_str()),
+        ))
+        .execute(conn);
+    match r {
+        // The address had already unsubscribed.
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+        r => r.map(|_| true).map_err(Error::from),
+    }
+}
+
+/// Returns whether an error is a violation of a unique index.
+fn is_unique_violation(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::Diesel(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether the given address has unsubscribed from all mailing lists, or from the given
+/// mailing list, if one is given.
+fn is_unsubscribed(
+    conn: &MysqlConnection,
+    email: &str,
+    mailing_list_id: Option<u32>,
+) -> Result<bool> {
+    diesel::select(diesel::dsl::exists(
+        mailer_unsubscribes::table
+            .filter(mailer_unsubscribes::email.eq(email))
+            .filter(
+                mailer_unsubscribes::mailing_list_id
+                    .eq(mailing_list_id)
+                    .or(mailer_unsubscribes::mailing_list_id.is_null()),
+            ),
+    )).get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Returns the time `duration` after `now`. Durations too long to fit in a MySQL `TIMESTAMP`,
+/// which may be configured to mean "effectively never", give the latest time it can hold instead.
+fn later_by(now: NaiveDateTime, duration: StdDuration) -> NaiveDateTime {
+    let latest = NaiveDate::from_ymd(2038, 1, 19).and_hms(3, 14, 7);
+    Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| now.checked_add_signed(duration))
+        .map_or(latest, |time| time.min(latest))
+}
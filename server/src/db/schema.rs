@@ -1,7 +1,20 @@
+table! {
+    mailer_broadcasts (id) {
+        id -> Unsigned<Integer>,
+        mailing_list_id -> Unsigned<Integer>,
+        template_id -> Unsigned<Integer>,
+        idempotency_key -> Nullable<Varchar>,
+        queued -> Unsigned<Integer>,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     mailer_lists (id) {
         id -> Unsigned<Integer>,
         name -> Varchar,
+        templates_version -> Unsigned<Integer>,
+        confirm_template_id -> Nullable<Unsigned<Integer>>,
     }
 }
 
@@ -14,6 +27,31 @@ table! {
         subject -> Varchar,
         send_started -> Bool,
         send_done -> Bool,
+        attempts -> Unsigned<Integer>,
+        last_error -> Nullable<Text>,
+        next_attempt -> Nullable<Timestamp>,
+        failed -> Bool,
+        claimed_by -> Nullable<Varchar>,
+        lease_expires -> Nullable<Timestamp>,
+        send_at -> Nullable<Timestamp>,
+        priority -> Integer,
+        idempotency_key -> Nullable<Varchar>,
+        cancelled -> Bool,
+        created_at -> Timestamp,
+        sent_at -> Nullable<Timestamp>,
+        confirmation -> Bool,
+    }
+}
+
+table! {
+    mailer_subscribers (id) {
+        id -> Unsigned<Integer>,
+        mailing_list_id -> Unsigned<Integer>,
+        email -> Varchar,
+        created_at -> Timestamp,
+        attributes -> Nullable<Longtext>,
+        confirmed -> Bool,
+        confirm_token -> Nullable<Varchar>,
     }
 }
 
@@ -31,17 +69,24 @@ table! {
     mailer_unsubscribes (id) {
         id -> Unsigned<Integer>,
         email -> Varchar,
-        mailing_list_id -> Unsigned<Integer>,
+        mailing_list_id -> Nullable<Unsigned<Integer>>,
+        unsubscribed_at -> Timestamp,
+        source -> Varchar,
+        mailing_list_key -> Unsigned<Integer>,
     }
 }
 
+joinable!(mailer_broadcasts -> mailer_lists (mailing_list_id));
 joinable!(mailer_queue -> mailer_templates (template_id));
+joinable!(mailer_subscribers -> mailer_lists (mailing_list_id));
 joinable!(mailer_templates -> mailer_lists (mailing_list_id));
 joinable!(mailer_unsubscribes -> mailer_lists (mailing_list_id));
 
 allow_tables_to_appear_in_same_query!(
+    mailer_broadcasts,
     mailer_lists,
     mailer_queue,
+    mailer_subscribers,
     mailer_templates,
     mailer_unsubscribes,
 );
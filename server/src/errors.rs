@@ -12,6 +12,14 @@ pub enum ErrorKind {
     #[fail(display = "Authentication required")]
     AuthenticationRequired,
 
+    /// The authentication server responded with an unexpected status code.
+    #[fail(display = "Unexpected response from the authentication server: {}", _0)]
+    AuthServerStatus(u16),
+
+    /// An item in the queue was attempted to be cancelled, but it has already started sending.
+    #[fail(display = "Queue item {} can no longer be cancelled", _0)]
+    CannotCancel(u32),
+
     /// Administrative privileges were needed, but the authenticated user does not have them.
     #[fail(display = "Insufficient privileges")]
     InsufficientPrivileges,
@@ -20,11 +28,46 @@ pub enum ErrorKind {
     #[fail(display = "{}", _0)]
     InvalidData(&'static str),
 
+    /// A signed link was used, but its token doesn't match or it has expired.
+    #[fail(display = "Invalid or expired link")]
+    InvalidToken,
+
+    /// A subscription was attempted on a mailing list without a confirmation template, which
+    /// doesn't accept subscriptions.
+    #[fail(display = "Mailing list {} doesn't accept subscriptions", _0)]
+    NoConfirmTemplate(u32),
+
     /// No authentication server exists. This is the case when no authentication server URL is
     /// provided.
     #[fail(display = "No authentication server exists")]
     NoAuthServer,
 
+    /// A mailing list was referred to by name, but no such list exists.
+    #[fail(display = "No mailing list named {:?} exists", _0)]
+    NoSuchMailingList(String),
+
+    /// A mailing list was referred to by ID, but no such list exists.
+    #[fail(display = "No mailing list with ID {} exists", _0)]
+    NoSuchMailingListId(u32),
+
+    /// An item in the queue was referred to by ID, but it either doesn't exist or isn't in a state
+    /// where the requested operation is possible.
+    #[fail(display = "No suitable queue item with ID {} exists", _0)]
+    NoSuchQueueItem(u32),
+
+    /// A subscriber was referred to by address, but no such subscriber exists in the mailing
+    /// list.
+    #[fail(display = "No subscriber with the address {:?} exists", _0)]
+    NoSuchSubscriber(String),
+
+    /// A template was referred to by name, but no such template exists in the mailing list.
+    #[fail(display = "No template named {:?} exists", _0)]
+    NoSuchTemplate(String),
+
+    /// A subscriber was attempted to be added, but they're already subscribed.
+    #[fail(display = "{:?} is already subscribed", _0)]
+    SubscriberExists(String),
+
     /// A template was attempted to be created, but it already exists.
     #[fail(display = "Template {:?} already exists", _0)]
     TemplateExists(String),
@@ -33,6 +76,10 @@ pub enum ErrorKind {
     #[fail(display = "Diesel error: {}", _0)]
     Diesel(::diesel::result::Error),
 
+    /// An I/O error.
+    #[fail(display = "I/O error: {}", _0)]
+    Io(::std::io::Error),
+
     /// An error from Lettre's mail builder.
     #[fail(display = "Mail builder error: {}", _0)]
     Mail(::lettre_email::error::Error),
@@ -41,6 +88,10 @@ pub enum ErrorKind {
     #[fail(display = "R2D2 error: {}", _0)]
     R2D2(::diesel::r2d2::PoolError),
 
+    /// An error from Reqwest.
+    #[fail(display = "HTTP client error: {}", _0)]
+    Reqwest(::reqwest::Error),
+
     /// An error deserializing JSON data.
     #[fail(display = "JSON error: {}", _0)]
     SerdeJson(::serde_json::error::Error),
@@ -56,6 +107,10 @@ pub enum ErrorKind {
     /// An error when constructing a URL.
     #[fail(display = "URL error: {}", _0)]
     Url(::url::ParseError),
+
+    /// An error from Warp.
+    #[fail(display = "Warp error: {}", _0)]
+    Warp(::warp::Error),
 }
 
 impl From<::diesel::result::Error> for ErrorKind {
@@ -64,6 +119,12 @@ impl From<::diesel::result::Error> for ErrorKind {
     }
 }
 
+impl From<::std::io::Error> for ErrorKind {
+    fn from(err: ::std::io::Error) -> ErrorKind {
+        ErrorKind::Io(err)
+    }
+}
+
 impl From<::lettre_email::error::Error> for ErrorKind {
     fn from(err: ::lettre_email::error::Error) -> ErrorKind {
         ErrorKind::Mail(err)
@@ -76,6 +137,12 @@ impl From<::diesel::r2d2::PoolError> for ErrorKind {
     }
 }
 
+impl From<::reqwest::Error> for ErrorKind {
+    fn from(err: ::reqwest::Error) -> ErrorKind {
+        ErrorKind::Reqwest(err)
+    }
+}
+
 impl From<::serde_json::error::Error> for ErrorKind {
     fn from(err: ::serde_json::error::Error) -> ErrorKind {
         ErrorKind::SerdeJson(err)
@@ -100,6 +167,12 @@ impl From<::url::ParseError> for ErrorKind {
     }
 }
 
+impl From<::warp::Error> for ErrorKind {
+    fn from(err: ::warp::Error) -> ErrorKind {
+        ErrorKind::Warp(err)
+    }
+}
+
 /// An application error.
 #[derive(Debug)]
 pub struct Error {
@@ -1,19 +1,30 @@
+extern crate bytes;
+extern crate chrono;
 #[macro_use]
 extern crate diesel;
 #[macro_use]
 extern crate failure;
+#[macro_use]
 extern crate futures;
+extern crate hmac;
+extern crate hyper;
 extern crate lettre;
 extern crate lettre_email;
 #[macro_use]
 extern crate log;
 extern crate pulldown_cmark;
+extern crate rand;
+extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate sha2;
 #[macro_use]
 extern crate tera;
+#[cfg(test)]
+extern crate tokio;
 extern crate tokio_threadpool;
 extern crate url;
 #[macro_use]
@@ -25,13 +36,23 @@ mod macros;
 mod db;
 mod errors;
 mod mailer;
+mod maintenance;
+pub mod metrics;
+mod signing;
 mod sweeper;
 mod web;
 
-pub use db::DB;
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+pub use db::{
+    FailedEmail, FailedFilter, NewBroadcast, NewBulkEmail, NewEmail, QueueState, QueueStatus,
+    QueuedEmail, Recipient, ScheduledEmail, SentEmail, Subscriber, UnsubscribeSource, DB,
+};
 pub use errors::{Error, ErrorKind, Result};
 pub use mailer::Mailer;
-pub use sweeper::sweep;
+pub use maintenance::{maintain, MaintenanceConfig, RetentionPolicy};
+pub use signing::Signer;
+pub use sweeper::{sweep, RetryPolicy, SweepConfig, Wakeup};
 pub use web::routes;
 
 /// Logs an error, including its causes and backtrace (if possible).
@@ -55,3 +76,8 @@ pub fn log_err(err: failure::Error) {
         error!("{}", bt);
     }
 }
+
+/// Formats a timestamp from the database as RFC 3339.
+fn format_time(time: NaiveDateTime) -> String {
+    DateTime::<Utc>::from_utc(time, Utc).to_rfc3339()
+}
@@ -1,4 +1,7 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
 
 use futures::{future::poll_fn, prelude::*};
 use lettre::{smtp::authentication::Credentials, EmailTransport, SmtpTransport};
@@ -11,59 +14,93 @@ use errors::{Error, ErrorKind, Result};
 #[derive(Clone)]
 pub struct Mailer {
     inner: Arc<MailerInner>,
-    transport: Arc<Mutex<SmtpTransport>>,
 }
 
 struct MailerInner {
     from: String,
+    next_transport: AtomicUsize,
     reply_to: String,
+    transports: Vec<Mutex<SmtpTransport>>,
+    unsubscribe_mailto: Option<String>,
 }
 
 impl Mailer {
-    /// Creates a new `Mailer`.
+    /// Creates a new `Mailer`, which will open at most the given number of connections to the
+    /// SMTP server. This is also the number of emails that can be in the process of being sent at
+    /// once. If an unsubscribe address is given, it's included in the `List-Unsubscribe` header
+    /// of every email, alongside the unsubscribe link.
     pub fn new(
         addr: String,
         from: String,
         user: String,
         pass: String,
         reply_to: Option<String>,
+        unsubscribe_mailto: Option<String>,
+        connections: usize,
     ) -> Result<Mailer> {
-        let transport = SmtpTransport::simple_builder(&addr)?
-            .credentials(Credentials::new(user, pass))
-            .smtp_utf8(true)
-            .build();
+        let transports = (0..connections.max(1))
+            .map(|_| -> Result<_> {
+                let transport = SmtpTransport::simple_builder(&addr)?
+                    .credentials(Credentials::new(user.clone(), pass.clone()))
+                    .smtp_utf8(true)
+                    .build();
+                Ok(Mutex::new(transport))
+            })
+            .collect::<Result<Vec<_>>>()?;
         let reply_to = reply_to.unwrap_or_else(|| from.clone());
         Ok(Mailer {
-            transport: Arc::new(Mutex::new(transport)),
-            inner: Arc::new(MailerInner { from, reply_to }),
+            inner: Arc::new(MailerInner {
+                from,
+                next_transport: AtomicUsize::new(0),
+                reply_to,
+                transports,
+                unsubscribe_mailto,
+            }),
         })
     }
 
+    /// Sends an email from a mailing list. The unsubscribe link is put in the `List-Unsubscribe`
+    /// header (RFC 2369), and marked as supporting one-click unsubscribing (RFC 8058).
     pub fn send_mail(
         &self,
         to: String,
         subject: String,
         body: String,
+        unsubscribe: String,
     ) -> impl Future<Item = (), Error = Error> {
+        let list_unsubscribe = match self.inner.unsubscribe_mailto {
+            Some(ref mailto) => {
+                format!("<{}>, <mailto:{}?subject=unsubscribe>", unsubscribe, mailto)
+            }
+            None => format!("<{}>", unsubscribe),
+        };
         let builder = EmailBuilder::new()
             .from(&self.inner.from as &str)
             .to(to)
             .reply_to(&self.inner.reply_to as &str)
             .subject(subject)
+            .header(("List-Unsubscribe", list_unsubscribe))
+            .header(("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"))
             .html(body);
         self.send_builder(builder)
     }
 
     fn send_builder(&self, email: EmailBuilder) -> impl Future<Item = (), Error = Error> {
-        let transport = self.transport.clone();
+        let inner = self.inner.clone();
+        let n = inner.next_transport.fetch_add(1, Ordering::Relaxed) % inner.transports.len();
         email
             .build()
             .map_err(Error::from)
             .into_future()
             .and_then(|email| {
                 poll_fn(move || {
-                    blocking(|| transport.lock().unwrap().send(&email).map_err(Error::from))
-                        .map_err(|_| panic!("Emails must be sent inside a Tokio thread pool!"))
+                    blocking(|| {
+                        inner.transports[n]
+                            .lock()
+                            .unwrap()
+                            .send(&email)
+                            .map_err(Error::from)
+                    }).map_err(|_| panic!("Emails must be sent inside a Tokio thread pool!"))
                 }).and_then(|r| {
                     r.and_then(|r: ::lettre::smtp::response::Response| {
                         if r.is_positive() {
@@ -1,3 +1,4 @@
+extern crate chrono;
 extern crate dotenv;
 #[macro_use]
 extern crate failure;
@@ -5,6 +6,7 @@ extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate mailer;
+extern crate rand;
 #[macro_use]
 extern crate structopt;
 extern crate syslog;
@@ -13,19 +15,34 @@ extern crate tokio_threadpool;
 extern crate url;
 extern crate warp;
 
+use std::env;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::process::exit;
+use std::path::PathBuf;
+use std::process::{self, exit};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use chrono::{DateTime, NaiveDateTime};
 use failure::Error;
 use futures::{Future, Stream};
-use mailer::{log_err, routes, sweep, Mailer, DB};
+use mailer::{
+    log_err, maintain, routes, sweep, FailedFilter, Mailer, MaintenanceConfig, RetentionPolicy,
+    RetryPolicy, Signer, SweepConfig, UnsubscribeSource, Wakeup, DB,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use structopt::StructOpt;
-use tokio::timer::Interval;
+use tokio::{runtime::Runtime, timer::Interval};
 use tokio_threadpool::ThreadPool;
 use url::Url;
 
+/// The shortest service authentication token accepted, in bytes. An empty token would let any
+/// request with an empty `auth` cookie through, and a short one could be guessed.
+const MIN_AUTH_TOKEN_LEN: usize = 16;
+
+/// The shortest signing key accepted, in bytes. This is the output size of HMAC-SHA256, so a
+/// shorter key would be the weakest part of the signature.
+const MIN_SIGNING_KEY_LEN: usize = 32;
+
 fn main() {
     dotenv::dotenv().ok();
     let options = Options::from_args();
@@ -37,38 +54,177 @@ fn main() {
     }
 }
 
-fn run(options: Options) -> Result<(), Error> {
+fn run(mut options: Options) -> Result<(), Error> {
+    match options.command.take() {
+        Some(Command::Failed(command)) => run_failed(&options.database_url, command),
+        Some(Command::Unsubscribe(args)) => run_unsubscribe(&options.database_url, args),
+        None => serve(options),
+    }
+}
+
+/// Runs a subcommand of `failed`.
+fn run_failed(database_url: &str, command: FailedCommand) -> Result<(), Error> {
+    let db = DB::connect(database_url)?;
+    let mut runtime = Runtime::new()?;
+    match command {
+        FailedCommand::List(filter) => {
+            let emails = runtime.block_on(db.list_failed(filter.into_filter()))?;
+            for email in emails {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    email.id,
+                    email.mailing_list,
+                    email.template,
+                    email.to_addr,
+                    email.created_at,
+                    email.attempts,
+                    email.last_error.unwrap_or_default()
+                );
+            }
+        }
+        FailedCommand::Requeue(filter) => {
+            let count = runtime.block_on(db.requeue_failed(filter.into_filter()))?;
+            println!("Requeued {} emails.", count);
+        }
+        FailedCommand::Discard(filter) => {
+            if filter.is_empty() && !filter.all {
+                bail!("Give at least one filter, or --all to discard every failed email");
+            }
+            let count = runtime.block_on(db.discard_failed(filter.into_filter()))?;
+            println!("Discarded {} emails.", count);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `unsubscribe` subcommand.
+fn run_unsubscribe(database_url: &str, args: UnsubscribeArgs) -> Result<(), Error> {
+    let db = DB::connect(database_url)?;
+    let mut runtime = Runtime::new()?;
+    let mailing_list_id = match args.mailing_list {
+        Some(name) => {
+            let lists = runtime.block_on(db.list_mailing_lists())?;
+            match lists.into_iter().find(|&(_, ref list)| *list == name) {
+                Some((id, _)) => Some(id),
+                None => bail!("No mailing list named {:?} exists", name),
+            }
+        }
+        None => None,
+    };
+    let source = if args.bounce {
+        UnsubscribeSource::Bounce
+    } else {
+        UnsubscribeSource::Admin
+    };
+    if runtime.block_on(db.unsubscribe(args.email.clone(), mailing_list_id, source))? {
+        println!("Unsubscribed {}.", args.email);
+    } else {
+        println!("{} was already unsubscribed.", args.email);
+    }
+    Ok(())
+}
+
+/// Serves the web interface and runs the sweeper.
+fn serve(options: Options) -> Result<(), Error> {
     let serve_addr = options.serve_addr()?;
+    let auth_token = required(options.auth_token.clone(), "AUTH_TOKEN")?;
+    if auth_token.len() < MIN_AUTH_TOKEN_LEN {
+        bail!("AUTH_TOKEN must be at least {} bytes long", MIN_AUTH_TOKEN_LEN);
+    }
+    let base_url = Arc::new(required(options.base_url.clone(), "BASE_URL")?);
+    let signing_key = required(options.signing_key.clone(), "SIGNING_KEY")?;
+    if signing_key.len() < MIN_SIGNING_KEY_LEN {
+        bail!("SIGNING_KEY must be at least {} bytes long", MIN_SIGNING_KEY_LEN);
+    }
+    let smtp_from = required(options.smtp_from.clone(), "SMTP_FROM")?;
+    let smtp_user = required(options.smtp_user.clone(), "SMTP_USER")?;
+    let smtp_pass = required(options.smtp_pass.clone(), "SMTP_PASS")?;
+    let signer = Signer::new(signing_key.as_bytes());
+    let sweep_config = Arc::new(SweepConfig {
+        base_url: base_url.clone(),
+        signer: signer.clone(),
+        unsubscribe_expiry: options
+            .unsubscribe_expiry
+            .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+        retry: options.retry_policy(),
+        worker_id: options.worker_id(),
+        lease: Duration::from_secs(options.lease),
+        concurrency: options.sweep_concurrency.max(1),
+        batch_size: options.claim_batch_size.max(1),
+    });
+    let maintenance_config = Arc::new(MaintenanceConfig {
+        retention: Arc::new(options.retention_policy()),
+        confirm_expiry: Duration::from_secs(options.confirm_expiry.saturating_mul(60 * 60)),
+    });
+
     let db = DB::connect(&options.database_url)?;
     let mailer = Mailer::new(
         options.smtp_addr,
-        options.smtp_from,
-        options.smtp_user,
-        options.smtp_pass,
+        smtp_from,
+        smtp_user,
+        smtp_pass,
         options.smtp_reply_to,
+        options.unsubscribe_mailto,
+        options.smtp_connections,
     )?;
 
-    let base_url = Arc::new(options.base_url);
-    let routes = routes(db.clone(), options.auth_server, base_url.clone());
+    let sweep_interval = Duration::from_secs(options.sweep_interval);
+    let (wakeup, wakeups) = Wakeup::new();
+    let routes = routes(
+        db.clone(),
+        options.auth_server,
+        auth_token,
+        base_url,
+        signer,
+        wakeup,
+    );
     let server = warp::serve(routes).bind(serve_addr);
 
     let thread_pool = ThreadPool::new();
     thread_pool.spawn(server);
-    let sweeper = Interval::new(Instant::now(), Duration::from_secs(5 * 60))
+
+    // Maintenance happens hourly, independently of sweeps.
+    let db2 = db.clone();
+    let maintenance = Interval::new(Instant::now(), Duration::from_secs(60 * 60))
         .map_err(Error::from)
         .for_each(move |_| {
-            let fut = sweep(db.clone(), mailer.clone(), base_url.clone());
-            Ok(thread_pool.spawn(fut.map_err(|e| log_err(e.into()))))
+            maintain(db2.clone(), maintenance_config.clone()).or_else(|e| {
+                log_err(e.into());
+                Ok(())
+            })
+        })
+        .map_err(log_err);
+
+    // Sweeps happen periodically, and whenever mail is queued. Each sweep finishes before the
+    // next one starts, so they never overlap.
+    let ticks = Interval::new(Instant::now(), sweep_interval)
+        .map(|_| ())
+        .map_err(Error::from);
+    let wakeups = wakeups.map_err(|()| format_err!("The sweeper's wakeup channel failed"));
+    let sweeper = ticks
+        .select(wakeups)
+        .for_each(move |()| {
+            sweep(db.clone(), mailer.clone(), sweep_config.clone()).or_else(|e| {
+                log_err(e.into());
+                Ok(())
+            })
         })
         .map_err(log_err);
 
-    tokio::run(sweeper);
+    tokio::run(futures::lazy(move || {
+        tokio::spawn(maintenance);
+        sweeper
+    }));
     Ok(())
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(raw(setting = "::structopt::clap::AppSettings::ColoredHelp"))]
 struct Options {
+    /// The command to run. If none is given, the mailer serves the web interface and sends mail.
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// Turns off message output.
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
@@ -77,49 +233,124 @@ struct Options {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
 
+    /// The token other services must present to use this one, at least 16 bytes long. Required to
+    /// serve.
+    #[structopt(long = "auth-token", env = "AUTH_TOKEN")]
+    auth_token: Option<String>,
+
     /// The URL of the authentication server to use, if any.
     #[structopt(short = "a", long = "auth-server", env = "AUTH_SERVER")]
     auth_server: Option<Url>,
 
-    /// The base URL for unsubscribe links and template examples.
+    /// The base URL for unsubscribe links and template examples. Required to serve.
     #[structopt(short = "b", long = "base-url", env = "BASE_URL")]
-    base_url: Url,
+    base_url: Option<Url>,
+
+    /// A directory to archive sent emails to before they're deleted from the queue, as
+    /// newline-delimited JSON.
+    #[structopt(long = "archive-dir", env = "ARCHIVE_DIR", parse(from_os_str))]
+    archive_dir: Option<PathBuf>,
+
+    /// The number of emails to claim from the queue at once.
+    #[structopt(long = "claim-batch-size", env = "CLAIM_BATCH_SIZE", default_value = "32")]
+    claim_batch_size: usize,
+
+    /// The number of hours a subscription may go unconfirmed before it's deleted.
+    #[structopt(long = "confirm-expiry", env = "CONFIRM_EXPIRY", default_value = "72")]
+    confirm_expiry: u64,
 
     /// The URL of the MySQL database.
     #[structopt(short = "d", long = "db", env = "DATABASE_URL")]
     database_url: String,
 
+    /// The number of days after which sent emails are deleted from the queue.
+    #[structopt(long = "delete-sent-after", env = "DELETE_SENT_AFTER")]
+    delete_sent_after: Option<u32>,
+
     /// The host to serve on.
     #[structopt(short = "h", long = "host", env = "HOST", default_value = "::")]
     host: String,
 
+    /// The number of seconds an email may be claimed for sending before it's assumed that the
+    /// mailer that claimed it crashed, and it's sent again.
+    #[structopt(long = "lease", env = "LEASE", default_value = "600")]
+    lease: u64,
+
+    /// The number of times to try sending an email before giving up on it.
+    #[structopt(long = "max-attempts", env = "MAX_ATTEMPTS", default_value = "8")]
+    max_attempts: u32,
+
     /// The port to serve on.
     #[structopt(short = "p", long = "port", env = "PORT", default_value = "8001")]
     port: u16,
 
+    /// The number of days after which the data of sent emails is deleted.
+    #[structopt(long = "purge-data-after", env = "PURGE_DATA_AFTER")]
+    purge_data_after: Option<u32>,
+
+    /// The number of seconds to wait before retrying a failed email. This doubles with each
+    /// failure.
+    #[structopt(long = "retry-delay", env = "RETRY_DELAY", default_value = "60")]
+    retry_delay: u64,
+
+    /// The maximum number of seconds to wait before retrying a failed email.
+    #[structopt(long = "retry-max-delay", env = "RETRY_MAX_DELAY", default_value = "21600")]
+    retry_max_delay: u64,
+
+    /// The secret key used to sign links in emails, at least 32 bytes long. Required to serve.
+    #[structopt(long = "signing-key", env = "SIGNING_KEY")]
+    signing_key: Option<String>,
+
+    /// The maximum number of connections to the SMTP server.
+    #[structopt(long = "smtp-connections", env = "SMTP_CONNECTIONS", default_value = "2")]
+    smtp_connections: usize,
+
     /// The SMTP server to use.
     #[structopt(long = "smtp-addr", env = "SMTP_ADDR", default_value = "smtp.gmail.com")]
     smtp_addr: String,
 
-    /// The SMTP From header to use.
+    /// The SMTP From header to use. Required to serve.
     #[structopt(long = "smtp-from", env = "SMTP_FROM")]
-    smtp_from: String,
+    smtp_from: Option<String>,
 
-    /// The SMTP username to use.
+    /// The SMTP username to use. Required to serve.
     #[structopt(long = "smtp-user", env = "SMTP_USER")]
-    smtp_user: String,
+    smtp_user: Option<String>,
 
-    /// The SMTP password to use.
+    /// The SMTP password to use. Required to serve.
     #[structopt(long = "smtp-pass", env = "SMTP_PASS")]
-    smtp_pass: String,
+    smtp_pass: Option<String>,
 
     /// The SMTP Reply-To header to use.
     #[structopt(long = "smtp-reply-to", env = "SMTP_REPLY_TO")]
     smtp_reply_to: Option<String>,
 
+    /// The number of emails the sweeper processes at once.
+    #[structopt(long = "sweep-concurrency", env = "SWEEP_CONCURRENCY", default_value = "8")]
+    sweep_concurrency: usize,
+
+    /// The number of seconds between sweeps, when no mail is queued in the meantime.
+    #[structopt(long = "sweep-interval", env = "SWEEP_INTERVAL", default_value = "300")]
+    sweep_interval: u64,
+
     /// The syslog server to send logs to.
     #[structopt(short = "s", long = "syslog-server", env = "SYSLOG_SERVER")]
     syslog_server: Option<String>,
+
+    /// The number of days after which unsubscribe and preference links stop working. If not
+    /// given, they work forever.
+    #[structopt(long = "unsubscribe-expiry", env = "UNSUBSCRIBE_EXPIRY")]
+    unsubscribe_expiry: Option<u64>,
+
+    /// An address to accept unsubscribe requests at, given in the List-Unsubscribe header of each
+    /// email alongside the unsubscribe link.
+    #[structopt(long = "unsubscribe-mailto", env = "UNSUBSCRIBE_MAILTO")]
+    unsubscribe_mailto: Option<String>,
+
+    /// A label for this instance of the mailer, recorded on the emails it claims along with a
+    /// random suffix. Defaults to the hostname and process ID.
+    #[structopt(long = "worker-id", env = "WORKER_ID")]
+    worker_id: Option<String>,
 }
 
 impl Options {
@@ -135,6 +366,39 @@ impl Options {
         }
     }
 
+    /// Gets the policy for how long sent emails are kept.
+    fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            purge_data_after: self.purge_data_after,
+            delete_after: self.delete_sent_after,
+            archive_dir: self.archive_dir.clone(),
+        }
+    }
+
+    /// Gets the policy for retrying failed emails.
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_secs(self.retry_delay),
+            max_delay: Duration::from_secs(self.retry_max_delay),
+            max_attempts: self.max_attempts,
+        }
+    }
+
+    /// Gets the identifier for this instance of the mailer. `WORKER_ID` is only used as a label,
+    /// since two instances could be given the same one by mistake; the random suffix keeps their
+    /// claims apart.
+    fn worker_id(&self) -> String {
+        let label = self.worker_id.clone().unwrap_or_else(|| {
+            let host = env::var("HOSTNAME").unwrap_or_else(|_| "mailer".to_string());
+            format!("{}-{}", host, process::id())
+        });
+        let suffix = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .collect::<String>();
+        format!("{}-{}", label, suffix)
+    }
+
     /// Sets up logging as specified by the `-q`, `-s`, and `-v` flags.
     fn start_logger(&self) {
         if !self.quiet {
@@ -167,3 +431,103 @@ impl Options {
         }
     }
 }
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Manages emails that failed to send too many times.
+    #[structopt(name = "failed")]
+    Failed(FailedCommand),
+
+    /// Unsubscribes an address from a mailing list, or from all mail.
+    #[structopt(name = "unsubscribe")]
+    Unsubscribe(UnsubscribeArgs),
+}
+
+#[derive(Debug, StructOpt)]
+enum FailedCommand {
+    /// Lists failed emails, with the error from their last attempt.
+    #[structopt(name = "list")]
+    List(FailedFilterArgs),
+
+    /// Puts failed emails back in the queue, to be sent by the running mailer's next sweep.
+    #[structopt(name = "requeue")]
+    Requeue(FailedFilterArgs),
+
+    /// Deletes failed emails from the queue.
+    #[structopt(name = "discard")]
+    Discard(FailedFilterArgs),
+}
+
+#[derive(Debug, StructOpt)]
+struct FailedFilterArgs {
+    /// Only match emails on the mailing list with this name.
+    #[structopt(long = "list")]
+    mailing_list: Option<String>,
+
+    /// Only match emails using the template with this name.
+    #[structopt(long = "template")]
+    template: Option<String>,
+
+    /// Only match emails queued at or after this RFC 3339 timestamp.
+    #[structopt(long = "since", parse(try_from_str = "parse_time"))]
+    since: Option<NaiveDateTime>,
+
+    /// Only match emails queued at or before this RFC 3339 timestamp.
+    #[structopt(long = "until", parse(try_from_str = "parse_time"))]
+    until: Option<NaiveDateTime>,
+
+    /// Only match emails whose last error contains this string.
+    #[structopt(long = "error")]
+    error: Option<String>,
+
+    /// Match every failed email. Discarding requires either this or at least one filter.
+    #[structopt(long = "all")]
+    all: bool,
+}
+
+impl FailedFilterArgs {
+    /// Returns whether no filters were given, so the arguments match every failed email.
+    fn is_empty(&self) -> bool {
+        self.mailing_list.is_none()
+            && self.template.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.error.is_none()
+    }
+
+    fn into_filter(self) -> FailedFilter {
+        FailedFilter {
+            mailing_list: self.mailing_list,
+            template: self.template,
+            since: self.since,
+            until: self.until,
+            error: self.error,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct UnsubscribeArgs {
+    /// The address to unsubscribe.
+    email: String,
+
+    /// The name of the mailing list to unsubscribe the address from. If not given, the address
+    /// is unsubscribed from all mail.
+    #[structopt(long = "list")]
+    mailing_list: Option<String>,
+
+    /// Records the unsubscribe as caused by a bounce, rather than by an administrator.
+    #[structopt(long = "bounce")]
+    bounce: bool,
+}
+
+/// Gets an option that's only needed to serve, failing with its environment variable's name if
+/// it wasn't given.
+fn required<T>(value: Option<T>, name: &str) -> Result<T, Error> {
+    value.ok_or_else(|| format_err!("{} must be set to serve the mailer", name))
+}
+
+/// Parses an RFC 3339 timestamp from the command line.
+fn parse_time(s: &str) -> Result<NaiveDateTime, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(s).map(|time| time.naive_utc())
+}
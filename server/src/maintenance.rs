@@ -0,0 +1,152 @@
+//! Housekeeping for the database, run periodically alongside the sweeper.
+
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures::{
+    future::{loop_fn, ok, poll_fn, Either, Loop},
+    prelude::*,
+};
+use tokio_threadpool::blocking;
+
+use db::SentEmail;
+use {format_time, Error, Result, DB};
+
+/// The number of emails deleted at a time.
+const DELETE_BATCH_SIZE: i64 = 1000;
+
+/// The configuration of the maintenance task.
+#[derive(Clone, Debug)]
+pub struct MaintenanceConfig {
+    /// How long sent emails are kept.
+    pub retention: Arc<RetentionPolicy>,
+
+    /// How long a subscription may go unconfirmed before it's deleted.
+    pub confirm_expiry: StdDuration,
+}
+
+/// How long sent emails are kept in the queue. Emails that haven't been sent are never affected.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    /// The number of days after which the data of a sent email is deleted.
+    pub purge_data_after: Option<u32>,
+
+    /// The number of days after which a sent email is deleted from the queue.
+    pub delete_after: Option<u32>,
+
+    /// A directory to write emails to before they're deleted. Emails are appended to a file
+    /// named after the current date, one JSON object per line.
+    pub archive_dir: Option<PathBuf>,
+}
+
+/// Does all the housekeeping: enforces the retention policy, and deletes expired subscriptions.
+pub fn maintain(db: DB, config: Arc<MaintenanceConfig>) -> impl Future<Item = (), Error = Error> {
+    let db2 = db.clone();
+    enforce_retention(db, config.retention.clone()).and_then(move |()| {
+        let cutoff = Duration::from_std(config.confirm_expiry)
+            .ok()
+            .and_then(|expiry| Utc::now().naive_utc().checked_sub_signed(expiry));
+        match cutoff {
+            Some(cutoff) => Either::A(db2.expire_subscriptions(cutoff).map(|expired| {
+                if expired > 0 {
+                    info!("Deleted {} unconfirmed subscriptions.", expired);
+                }
+            })),
+            None => Either::B(ok(())),
+        }
+    })
+}
+
+/// Purges and deletes sent emails according to the retention policy.
+fn enforce_retention(
+    db: DB,
+    policy: Arc<RetentionPolicy>,
+) -> impl Future<Item = (), Error = Error> {
+    // A number of days too large to count back from now means data is effectively kept forever.
+    let purge = match policy.purge_data_after.and_then(days_ago) {
+        Some(cutoff) => Either::A(db.purge_sent_data(cutoff).map(|purged| {
+            if purged > 0 {
+                info!("Purged the data of {} sent emails.", purged);
+            }
+        })),
+        None => Either::B(ok(())),
+    };
+    purge.and_then(move |()| match policy.delete_after.and_then(days_ago) {
+        Some(cutoff) => Either::A(delete_sent(db, policy.clone(), cutoff).map(|deleted| {
+            if deleted > 0 {
+                info!("Deleted {} sent emails.", deleted);
+            }
+        })),
+        None => Either::B(ok(())),
+    })
+}
+
+/// Deletes the emails sent before the given time, a batch at a time, archiving each batch first
+/// if the policy calls for it. Returns the number of emails deleted.
+fn delete_sent(
+    db: DB,
+    policy: Arc<RetentionPolicy>,
+    before: NaiveDateTime,
+) -> impl Future<Item = usize, Error = Error> {
+    loop_fn(0, move |deleted| {
+        let db = db.clone();
+        let policy = policy.clone();
+        db.list_sent_before(before, DELETE_BATCH_SIZE)
+            .and_then(move |emails| {
+                if emails.is_empty() {
+                    return Either::A(ok(Loop::Break(deleted)));
+                }
+
+                let ids = emails.iter().map(|email| email.id).collect::<Vec<_>>();
+                let archived = match policy.archive_dir.clone() {
+                    Some(dir) => Either::A(archive(dir, emails)),
+                    None => Either::B(ok(())),
+                };
+                Either::B(
+                    archived
+                        .and_then(move |()| db.delete_emails(ids))
+                        .map(move |n| Loop::Continue(deleted + n)),
+                )
+            })
+    })
+}
+
+/// Appends the emails to today's archive file in the given directory.
+fn archive(dir: PathBuf, emails: Vec<SentEmail>) -> impl Future<Item = (), Error = Error> {
+    let mut emails = Some(emails);
+    poll_fn(move || {
+        blocking(|| -> Result<()> {
+            let emails = emails.take().unwrap_or_default();
+            create_dir_all(&dir)?;
+            let path = dir.join(format!("mailer_queue-{}.ndjson", Utc::now().format("%Y-%m-%d")));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut file = BufWriter::new(file);
+            for email in emails {
+                let line = json!({
+                    "id": email.id,
+                    "mailing_list": email.mailing_list,
+                    "template": email.template,
+                    "email": email.to_addr,
+                    "subject": email.subject,
+                    "data": email.data,
+                    "created_at": format_time(email.created_at),
+                    "sent_at": email.sent_at.map(format_time),
+                });
+                writeln!(file, "{}", line)?;
+            }
+            file.flush()?;
+            Ok(())
+        }).map_err(|_| panic!("Archives must be written inside a Tokio thread pool!"))
+    }).and_then(|r| r)
+}
+
+/// Returns the time the given number of days ago, or `None` if that's too long ago to represent.
+fn days_ago(days: u32) -> Option<NaiveDateTime> {
+    Duration::from_std(StdDuration::from_secs(u64::from(days) * 24 * 60 * 60))
+        .ok()
+        .and_then(|ago| Utc::now().naive_utc().checked_sub_signed(ago))
+}
@@ -0,0 +1,68 @@
+//! Counters describing what the mailer has done, served in the Prometheus text format at
+//! `/metrics`.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A count that only goes up.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicUsize,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter {
+            name,
+            help,
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds to the counter.
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counter.
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Emails whose leases expired before they were sent, and which were released to be sent again.
+pub static LEASES_RECLAIMED: Counter = Counter::new(
+    "mailer_leases_reclaimed_total",
+    "Queued emails whose lease expired before they were sent.",
+);
+
+/// Templates loaded from the cache of compiled templates.
+pub static TEMPLATE_CACHE_HITS: Counter = Counter::new(
+    "mailer_template_cache_hits_total",
+    "Template loads served by the cache of compiled templates.",
+);
+
+/// Templates that had to be compiled, because their compiled form wasn't cached or was out of
+/// date.
+pub static TEMPLATE_CACHE_MISSES: Counter = Counter::new(
+    "mailer_template_cache_misses_total",
+    "Template loads that had to compile the mailing list's templates.",
+);
+
+static COUNTERS: &[&Counter] = &[
+    &LEASES_RECLAIMED,
+    &TEMPLATE_CACHE_HITS,
+    &TEMPLATE_CACHE_MISSES,
+];
+
+/// Renders every counter in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    for counter in COUNTERS {
+        writeln!(out, "# HELP {} {}", counter.name, counter.help).unwrap();
+        writeln!(out, "# TYPE {} counter", counter.name).unwrap();
+        writeln!(out, "{} {}", counter.name, counter.get()).unwrap();
+    }
+    out
+}
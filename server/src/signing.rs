@@ -0,0 +1,282 @@
+//! Signing of the links sent in emails, so that only the recipient of an email can use them.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult, Write};
+use std::sync::Arc;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Signs and verifies tokens with a secret key. Cheaply clonable.
+#[derive(Clone)]
+pub struct Signer {
+    key: Arc<Vec<u8>>,
+}
+
+impl Signer {
+    /// Creates a signer with the given secret key.
+    pub fn new(key: &[u8]) -> Signer {
+        Signer {
+            key: Arc::new(key.to_vec()),
+        }
+    }
+
+    /// Returns the token for a link to the mailing preferences of the given address. If `expires`
+    /// is given, it's the Unix time after which the token is no longer valid.
+    pub fn preferences_token(&self, email: &str, expires: Option<i64>) -> String {
+        encode_hex(&self.preferences_mac(email, expires).result().code())
+    }
+
+    /// Returns the token for a link resubscribing the given address to the mailing list with the
+    /// given ID. If `expires` is given, it's the Unix time after which the token is no longer
+    /// valid.
+    pub fn resubscribe_token(
+        &self,
+        email: &str,
+        mailing_list_id: u32,
+        expires: Option<i64>,
+    ) -> String {
+        encode_hex(
+            &self
+                .list_mac(b"resubscribe\0", email, mailing_list_id, expires)
+                .result()
+                .code(),
+        )
+    }
+
+    /// Returns the token for a link unsubscribing the given address from the mailing list with
+    /// the given ID. If `expires` is given, it's the Unix time after which the token is no longer
+    /// valid.
+    pub fn unsubscribe_token(
+        &self,
+        email: &str,
+        mailing_list_id: u32,
+        expires: Option<i64>,
+    ) -> String {
+        encode_hex(
+            &self
+                .list_mac(b"unsubscribe\0", email, mailing_list_id, expires)
+                .result()
+                .code(),
+        )
+    }
+
+    /// Checks that a token is valid for a link to the mailing preferences of the given address, and
+    /// that it hasn't expired.
+    pub fn verify_preferences_token(&self, email: &str, expires: Option<i64>, token: &str) -> bool {
+        if is_expired(expires) {
+            return false;
+        }
+        match decode_hex(token) {
+            Some(code) => self.preferences_mac(email, expires).verify(&code).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Checks that a token is valid for a link resubscribing the given address to the mailing list
+    /// with the given ID, and that it hasn't expired.
+    pub fn verify_resubscribe_token(
+        &self,
+        email: &str,
+        mailing_list_id: u32,
+        expires: Option<i64>,
+        token: &str,
+    ) -> bool {
+        self.verify_list_token(b"resubscribe\0", email, mailing_list_id, expires, token)
+    }
+
+    /// Checks that a token is valid for a link unsubscribing the given address from the mailing
+    /// list with the given ID, and that it hasn't expired.
+    pub fn verify_unsubscribe_token(
+        &self,
+        email: &str,
+        mailing_list_id: u32,
+        expires: Option<i64>,
+        token: &str,
+    ) -> bool {
+        self.verify_list_token(b"unsubscribe\0", email, mailing_list_id, expires, token)
+    }
+
+    fn verify_list_token(
+        &self,
+        prefix: &[u8],
+        email: &str,
+        mailing_list_id: u32,
+        expires: Option<i64>,
+        token: &str,
+    ) -> bool {
+        if is_expired(expires) {
+            return false;
+        }
+        match decode_hex(token) {
+            Some(code) => self
+                .list_mac(prefix, email, mailing_list_id, expires)
+                .verify(&code)
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    fn preferences_mac(&self, email: &str, expires: Option<i64>) -> Hmac<Sha256> {
+        let mut mac = self.mac();
+        mac.input(b"preferences\0");
+        if let Some(expires) = expires {
+            mac.input(expires.to_string().as_bytes());
+        }
+        mac.input(b"\0");
+        mac.input(email.as_bytes());
+        mac
+    }
+
+    fn list_mac(
+        &self,
+        prefix: &[u8],
+        email: &str,
+        mailing_list_id: u32,
+        expires: Option<i64>,
+    ) -> Hmac<Sha256> {
+        let mut mac = self.mac();
+        mac.input(prefix);
+        mac.input(mailing_list_id.to_string().as_bytes());
+        mac.input(b"\0");
+        if let Some(expires) = expires {
+            mac.input(expires.to_string().as_bytes());
+        }
+        mac.input(b"\0");
+        mac.input(email.as_bytes());
+        mac
+    }
+
+    /// Returns a MAC keyed with the secret key. Each kind of token starts its message with a
+    /// different prefix, and only the last field, the address, can contain NULs, so separating the
+    /// fields with NULs means different tokens can't be made from the same message.
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts keys of any length")
+    }
+}
+
+impl Debug for Signer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        // The key is left out, so it doesn't end up in logs.
+        fmt.write_str("Signer")
+    }
+}
+
+/// Encodes bytes as a string of hexadecimal digits.
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+/// Checks whether the given expiry time has passed.
+fn is_expired(expires: Option<i64>) -> bool {
+    expires
+        .map(|expires| expires < Utc::now().timestamp())
+        .unwrap_or(false)
+}
+
+/// Decodes a string of hexadecimal digits, returning `None` if it's invalid.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` accepts a leading sign, so the digits are checked first.
+    if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{decode_hex, Signer};
+
+    const EMAIL: &str = "someone@example.com";
+
+    /// Returns a time an hour from now.
+    fn in_an_hour() -> Option<i64> {
+        Some(Utc::now().timestamp() + 60 * 60)
+    }
+
+    #[test]
+    fn preferences_tokens_round_trip() {
+        let signer = Signer::new(b"secret");
+        for &expires in &[None, in_an_hour()] {
+            let token = signer.preferences_token(EMAIL, expires);
+            assert!(signer.verify_preferences_token(EMAIL, expires, &token));
+            assert!(!signer.verify_preferences_token("other@example.com", expires, &token));
+            assert!(!signer.verify_preferences_token(EMAIL, expires.map(|t| t + 1), &token));
+        }
+        let token = signer.preferences_token(EMAIL, in_an_hour());
+        assert!(!signer.verify_preferences_token(EMAIL, None, &token));
+    }
+
+    #[test]
+    fn list_tokens_round_trip() {
+        let signer = Signer::new(b"secret");
+        for &expires in &[None, in_an_hour()] {
+            let token = signer.unsubscribe_token(EMAIL, 1, expires);
+            assert!(signer.verify_unsubscribe_token(EMAIL, 1, expires, &token));
+            assert!(!signer.verify_unsubscribe_token("other@example.com", 1, expires, &token));
+            assert!(!signer.verify_unsubscribe_token(EMAIL, 2, expires, &token));
+            assert!(!signer.verify_unsubscribe_token(EMAIL, 1, expires.map(|t| t + 1), &token));
+
+            let token = signer.resubscribe_token(EMAIL, 1, expires);
+            assert!(signer.verify_resubscribe_token(EMAIL, 1, expires, &token));
+            assert!(!signer.verify_resubscribe_token("other@example.com", 1, expires, &token));
+            assert!(!signer.verify_resubscribe_token(EMAIL, 2, expires, &token));
+            assert!(!signer.verify_resubscribe_token(EMAIL, 1, expires.map(|t| t + 1), &token));
+        }
+    }
+
+    #[test]
+    fn tokens_depend_on_the_key() {
+        let token = Signer::new(b"secret").unsubscribe_token(EMAIL, 1, None);
+        assert!(!Signer::new(b"other secret").verify_unsubscribe_token(EMAIL, 1, None, &token));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let signer = Signer::new(b"secret");
+        let expires = Some(Utc::now().timestamp() - 60);
+        let token = signer.preferences_token(EMAIL, expires);
+        assert!(!signer.verify_preferences_token(EMAIL, expires, &token));
+        let token = signer.resubscribe_token(EMAIL, 1, expires);
+        assert!(!signer.verify_resubscribe_token(EMAIL, 1, expires, &token));
+        let token = signer.unsubscribe_token(EMAIL, 1, expires);
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, expires, &token));
+    }
+
+    #[test]
+    fn tokens_are_rejected_for_other_kinds_of_link() {
+        let signer = Signer::new(b"secret");
+        let token = signer.unsubscribe_token(EMAIL, 1, None);
+        assert!(!signer.verify_resubscribe_token(EMAIL, 1, None, &token));
+        assert!(!signer.verify_preferences_token(EMAIL, None, &token));
+        let token = signer.resubscribe_token(EMAIL, 1, None);
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, None, &token));
+        let token = signer.preferences_token(EMAIL, None);
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, None, &token));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let signer = Signer::new(b"secret");
+        let token = signer.unsubscribe_token(EMAIL, 1, None);
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, None, &token[1..]));
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, None, &format!("{}zz", token)));
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, None, ""));
+        assert!(!signer.verify_unsubscribe_token(EMAIL, 1, None, "é"));
+
+        assert_eq!(decode_hex("0aF1"), Some(vec![0x0a, 0xf1]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+f"), None);
+        assert_eq!(decode_hex("é"), None);
+    }
+}
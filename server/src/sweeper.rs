@@ -1,39 +1,160 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use futures::{prelude::*, stream::poll_fn};
-use serde_json::{self, Value};
+use chrono::{Duration as ChronoDuration, Utc};
+use futures::{
+    future::{ok, Either},
+    prelude::*,
+    stream::{iter_ok, poll_fn},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use serde_json::{self, Map, Value};
 use url::Url;
 
-use {log_err, Error, Mailer, DB};
+use db::QueuedEmail;
+use {log_err, metrics, Error, Mailer, Signer, DB};
+
+/// The configuration of the sweeper.
+#[derive(Clone, Debug)]
+pub struct SweepConfig {
+    /// The base URL for unsubscribe links.
+    pub base_url: Arc<Url>,
+
+    /// Signs unsubscribe and preference links.
+    pub signer: Signer,
+
+    /// How long unsubscribe and preference links stay valid. If `None`, they never expire.
+    pub unsubscribe_expiry: Option<Duration>,
+
+    /// How failed sends are retried.
+    pub retry: RetryPolicy,
+
+    /// An identifier for this instance of the mailer, recorded on the emails it claims. This must
+    /// be unique to the process, since an instance takes every email claimed under its ID as its
+    /// own.
+    pub worker_id: String,
+
+    /// How long a claim on an email lasts. If the email hasn't been sent by the time its lease
+    /// expires, it's assumed that whichever instance claimed it crashed, and it will be sent
+    /// again.
+    pub lease: Duration,
+
+    /// The number of emails to process at once.
+    pub concurrency: usize,
+
+    /// The number of emails to claim from the queue at once. Every email in a batch is claimed
+    /// when the batch is, so the lease must be long enough to send the whole batch.
+    pub batch_size: usize,
+}
+
+/// How sends that fail are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// The delay before the first retry. Each retry after that waits twice as long as the one
+    /// before it.
+    pub base_delay: Duration,
+
+    /// The longest delay between retries.
+    pub max_delay: Duration,
+
+    /// The number of attempts after which an email is marked as permanently failed.
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the next attempt, given how many attempts have been made so far.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let doublings = attempts.saturating_sub(1).min(31);
+        self.base_delay
+            .checked_mul(1 << doublings)
+            .map(|delay| delay.min(self.max_delay))
+            .unwrap_or(self.max_delay)
+    }
+}
+
+/// A handle for waking the sweeper when there's new mail to send. Cheaply clonable.
+#[derive(Clone)]
+pub struct Wakeup {
+    sender: Arc<Mutex<Sender<()>>>,
+}
+
+impl Wakeup {
+    /// Creates a new `Wakeup`, along with the stream of wakeups it produces.
+    pub fn new() -> (Wakeup, Receiver<()>) {
+        let (sender, receiver) = channel(0);
+        let wakeup = Wakeup {
+            sender: Arc::new(Mutex::new(sender)),
+        };
+        (wakeup, receiver)
+    }
+
+    /// Wakes the sweeper. Wakeups that arrive while one is already pending are merged into it.
+    pub fn wake(&self) {
+        // This fails if a wakeup is already pending, or if the sweeper has stopped, neither of
+        // which is a problem.
+        let _ = self.sender.lock().unwrap().try_send(());
+    }
+}
 
 /// Sweeps all unsent emails from the database (by sending them).
-pub fn sweep(db: DB, mailer: Mailer, base_url: Arc<Url>) -> impl Future<Item = (), Error = Error> {
+pub fn sweep(
+    db: DB,
+    mailer: Mailer,
+    config: Arc<SweepConfig>,
+) -> impl Future<Item = (), Error = Error> {
     info!("Started sweeping.");
-    get_all_unsent(db.clone())
-        .and_then(
-            move |(id, mailing_list_id, template_id, to_addr, subject, data)| {
-                let base_url = base_url.clone();
-                let db2 = db.clone();
-                let mailer2 = mailer.clone();
-
-                db.load_template(template_id)
-                    .join(serde_json::from_str::<Value>(&data).map_err(Error::from))
-                    .and_then(move |(render, data)| {
-                        let mut unsubscribe = base_url
-                            .join("unsubscribe")?
-                            .join(&mailing_list_id.to_string())?;
-                        unsubscribe
-                            .query_pairs_mut()
-                            .clear()
-                            .append_pair("email", &to_addr);
-                        render(context! {data: data, unsubscribe: unsubscribe.to_string()})
-                            .map(|body| (to_addr, body))
+    db.reclaim_expired_leases()
+        .map(|reclaimed| {
+            metrics::LEASES_RECLAIMED.add(reclaimed);
+            if reclaimed > 0 {
+                warn!("Reclaimed {} emails with expired leases.", reclaimed);
+            }
+        })
+        .and_then(move |()| send_all(db, mailer, config))
+}
+
+/// Sends every email that's ready to be sent.
+fn send_all(
+    db: DB,
+    mailer: Mailer,
+    config: Arc<SweepConfig>,
+) -> impl Future<Item = (), Error = Error> {
+    let concurrency = config.concurrency;
+    get_all_unsent(db.clone(), config.clone())
+        .map(move |email| {
+            // Errors in claiming emails or recording the outcome of a send are counted as failures
+            // like any other, rather than aborting the sweep and leaving the rest of the claimed
+            // emails to wait out their leases.
+            let email = match email {
+                Ok(email) => email,
+                Err(e) => return Either::B(ok(Err(e))),
+            };
+            let db2 = db.clone();
+            let id = email.id;
+            let retry = config.retry;
+            let worker_id = config.worker_id.clone();
+            let worker_id2 = config.worker_id.clone();
+            Either::A(
+                send_one(&db, &mailer, config.clone(), email)
+                    .then(move |r| match r {
+                        Ok(()) => Either::A(db2.set_email_done(id, worker_id).map(|()| Ok(()))),
+                        Err(e) => Either::B(
+                            db2.set_email_failed(id, worker_id2, e.to_string(), retry)
+                                .map(move |permanent| {
+                                    if permanent {
+                                        error!(
+                                            "Giving up on email {} after too many failures.",
+                                            id
+                                        );
+                                    }
+                                    Err(e)
+                                }),
+                        ),
                     })
-                    .and_then(move |(to_addr, body)| mailer2.send_mail(to_addr, subject, body))
-                    .and_then(move |()| db2.set_email_done(id))
-            },
-        )
-        .then(Ok)
+                    .then(|r| Ok::<_, Error>(r.and_then(|r| r))),
+            )
+        })
+        .buffer_unordered(concurrency)
         .fold((0, 0), |(succ, fail), r| -> Result<_, Error> {
             Ok(match r {
                 Ok(()) => (succ + 1, fail),
@@ -57,17 +178,122 @@ pub fn sweep(db: DB, mailer: Mailer, base_url: Arc<Url>) -> impl Future<Item = (
         })
 }
 
+/// Renders and sends a single email.
+fn send_one(
+    db: &DB,
+    mailer: &Mailer,
+    config: Arc<SweepConfig>,
+    email: QueuedEmail,
+) -> impl Future<Item = (), Error = Error> {
+    let mailer = mailer.clone();
+    let QueuedEmail {
+        mailing_list_id,
+        template_id,
+        to_addr,
+        subject,
+        data,
+        attributes,
+        ..
+    } = email;
+    let data = serde_json::from_str::<Value>(&data).map_err(Error::from);
+    let subscriber = match attributes {
+        Some(attributes) => serde_json::from_str::<Value>(&attributes).map_err(Error::from),
+        None => Ok(Value::Object(Map::new())),
+    };
+    db.load_template(template_id)
+        .join3(data, subscriber)
+        .and_then(move |(render, data, subscriber)| {
+            let expires = link_expiry(&config);
+            let preferences = preferences_url(&config, &to_addr, expires)?;
+            let unsubscribe =
+                unsubscribe_url(&config, &to_addr, mailing_list_id, expires)?.to_string();
+            render(context! {
+                data: data,
+                preferences: preferences.to_string(),
+                subscriber: subscriber,
+                unsubscribe: unsubscribe
+            })
+                .map(|body| (to_addr, body, unsubscribe))
+        })
+        .and_then(move |(to_addr, body, unsubscribe)| {
+            mailer.send_mail(to_addr, subject, body, unsubscribe)
+        })
+}
+
+/// Returns the Unix time at which links sent now expire, if they do.
+fn link_expiry(config: &SweepConfig) -> Option<i64> {
+    config.unsubscribe_expiry.map(|expiry| {
+        let expiry =
+            ChronoDuration::from_std(expiry).unwrap_or_else(|_| ChronoDuration::max_value());
+        Utc::now()
+            .checked_add_signed(expiry)
+            .map(|expires| expires.timestamp())
+            .unwrap_or(i64::max_value())
+    })
+}
+
+/// Builds the signed link to the mailing preferences of the given address.
+fn preferences_url(config: &SweepConfig, email: &str, expires: Option<i64>) -> Result<Url, Error> {
+    let token = config.signer.preferences_token(email, expires);
+    let mut url = config.base_url.join("preferences")?;
+    signed_query(&mut url, email, expires, &token);
+    Ok(url)
+}
+
+/// Builds the signed link for unsubscribing the given address from the mailing list with the given
+/// ID.
+fn unsubscribe_url(
+    config: &SweepConfig,
+    email: &str,
+    mailing_list_id: u32,
+    expires: Option<i64>,
+) -> Result<Url, Error> {
+    let token = config.signer.unsubscribe_token(email, mailing_list_id, expires);
+    let mut url = config
+        .base_url
+        .join("unsubscribe/")?
+        .join(&mailing_list_id.to_string())?;
+    signed_query(&mut url, email, expires, &token);
+    Ok(url)
+}
+
+/// Sets the query string of a signed link.
+fn signed_query(url: &mut Url, email: &str, expires: Option<i64>, token: &str) {
+    let mut query = url.query_pairs_mut();
+    query.clear().append_pair("email", email);
+    if let Some(expires) = expires {
+        query.append_pair("expires", &expires.to_string());
+    }
+    query.append_pair("token", token);
+}
+
+/// Claims the emails that are ready to be sent, a batch at a time. If claiming a batch fails, the
+/// error is the last item of the stream.
 fn get_all_unsent(
     db: DB,
-) -> impl Stream<Item = (u32, u32, u32, String, String, String), Error = Error> {
-    let mut fut = db.get_next_to_send();
-    poll_fn(move || loop {
-        match fut.poll() {
-            Ok(Async::Ready(Some(data))) => {
-                fut = db.get_next_to_send();
-                return Ok(Async::Ready(Some(data)));
+    config: Arc<SweepConfig>,
+) -> impl Stream<Item = Result<QueuedEmail, Error>, Error = Error> {
+    let batch_size = config.batch_size as i64;
+    let mut fut = Some(db.get_next_batch_to_send(
+        config.worker_id.clone(),
+        config.lease,
+        batch_size,
+    ));
+    poll_fn(move || {
+        let batch = match fut.as_mut().map(|fut| fut.poll()) {
+            Some(Ok(Async::Ready(batch))) => batch,
+            Some(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+            Some(Err(e)) => {
+                fut = None;
+                return Ok(Async::Ready(Some(iter_ok::<_, Error>(vec![Err(e)]))));
             }
-            val => return val,
+            None => return Ok(Async::Ready(None)),
+        };
+        if batch.is_empty() {
+            return Ok(Async::Ready(None));
         }
-    })
+        fut = Some(db.get_next_batch_to_send(config.worker_id.clone(), config.lease, batch_size));
+        let batch = batch.into_iter().map(Ok).collect::<Vec<_>>();
+        Ok(Async::Ready(Some(iter_ok::<_, Error>(batch))))
+    }).flatten()
 }
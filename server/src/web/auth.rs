@@ -0,0 +1,168 @@
+//! Authentication of requests.
+
+use std::sync::Arc;
+
+use futures::{
+    future::{err, Either},
+    prelude::*,
+};
+use reqwest::{async::Client, header::COOKIE, StatusCode};
+use url::Url;
+use warp::{self, filters::BoxedFilter, Filter, Rejection};
+
+use {Error, ErrorKind, Result};
+
+/// Returns a filter that checks that the caller is an administrator, by asking the identity server
+/// at `auth_server_url` about the caller's `auth` cookie. Like `service_auth`, this extracts the
+/// outcome rather than rejecting.
+///
+/// The identity server is sent a `GET` request to `/is-admin`, with the cookie forwarded. It
+/// should respond with a 200 if the cookie belongs to an administrator, a 403 if it belongs to
+/// anyone else, or a 401 if it's invalid.
+pub fn admin_auth(client: Client, auth_server_url: Option<Url>) -> BoxedFilter<(Result<()>,)> {
+    let auth_server_url = Arc::new(auth_server_url);
+    warp::cookie::optional("auth")
+        .and_then(move |cookie: Option<String>| {
+            check_admin(&client, (*auth_server_url).as_ref(), cookie)
+                .then(|r| Ok::<_, Rejection>(r))
+        })
+        .boxed()
+}
+
+fn check_admin(
+    client: &Client,
+    auth_server_url: Option<&Url>,
+    cookie: Option<String>,
+) -> impl Future<Item = (), Error = Error> {
+    let url = match auth_server_url.map(is_admin_url) {
+        Some(Ok(url)) => url,
+        Some(Err(e)) => return Either::B(err(e)),
+        None => return Either::B(err(ErrorKind::NoAuthServer.into())),
+    };
+    let cookie = match cookie {
+        Some(cookie) => cookie,
+        None => return Either::B(err(ErrorKind::AuthenticationRequired.into())),
+    };
+
+    Either::A(
+        client
+            .get(url)
+            .header(COOKIE, format!("auth={}", cookie))
+            .send()
+            .map_err(Error::from)
+            .and_then(|res| match res.status() {
+                StatusCode::OK => Ok(()),
+                StatusCode::UNAUTHORIZED => Err(ErrorKind::AuthenticationRequired.into()),
+                StatusCode::FORBIDDEN => Err(ErrorKind::InsufficientPrivileges.into()),
+                status => Err(ErrorKind::AuthServerStatus(status.as_u16()).into()),
+            }),
+    )
+}
+
+/// Returns the URL of the `is-admin` endpoint of the identity server. The server's URL is treated
+/// as a directory even without a trailing slash, so `https://auth.example/api` gives
+/// `https://auth.example/api/is-admin`.
+fn is_admin_url(auth_server_url: &Url) -> Result<Url> {
+    let mut url = auth_server_url.clone();
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url.join("is-admin").map_err(Error::from)
+}
+
+/// Returns a filter that checks for the service authentication token, given either as a bearer
+/// token in the `Authorization` header or as the `auth` cookie. Rather than rejecting, the filter
+/// extracts an `ErrorKind::AuthenticationRequired` error, so that the handler can respond with a
+/// 401.
+pub fn service_auth(token: Arc<String>) -> BoxedFilter<(Result<()>,)> {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional("auth"))
+        .map(move |header: Option<String>, cookie: Option<String>| {
+            let bearer = header.as_ref().and_then(|header| {
+                if header.starts_with("Bearer ") {
+                    Some(header["Bearer ".len()..].trim())
+                } else {
+                    None
+                }
+            });
+            match bearer.or_else(|| cookie.as_ref().map(|s| s as &str)) {
+                Some(presented)
+                    if !presented.is_empty()
+                        && constant_time_eq(presented.as_bytes(), token.as_bytes()) =>
+                {
+                    Ok(())
+                }
+                _ => Err(ErrorKind::AuthenticationRequired.into()),
+            }
+        })
+        .boxed()
+}
+
+/// Compares two byte strings without short-circuiting, so the comparison doesn't leak how much of
+/// a secret was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use hyper::{service::service_fn_ok, Body, Request, Response, Server, StatusCode};
+    use reqwest::async::Client;
+    use tokio::runtime::Runtime;
+    use url::Url;
+
+    use super::check_admin;
+    use {ErrorKind, Result};
+
+    /// Starts a stub identity server under `/api`, which treats the `admin` cookie as an
+    /// administrator's and the `user` cookie as anyone else's, and returns its URL.
+    fn stub_identity_server(runtime: &mut Runtime) -> Url {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn_ok(|req: Request<Body>| {
+                let cookie = req
+                    .headers()
+                    .get("cookie")
+                    .and_then(|cookie| cookie.to_str().ok())
+                    .unwrap_or("")
+                    .to_string();
+                let status = match (req.uri().path(), &cookie as &str) {
+                    ("/api/is-admin", "auth=admin") => StatusCode::OK,
+                    ("/api/is-admin", "auth=user") => StatusCode::FORBIDDEN,
+                    ("/api/is-admin", _) => StatusCode::UNAUTHORIZED,
+                    _ => StatusCode::NOT_FOUND,
+                };
+                let mut res = Response::new(Body::empty());
+                *res.status_mut() = status;
+                res
+            })
+        });
+        let url = format!("http://{}/api", server.local_addr()).parse().unwrap();
+        runtime.spawn(server.map_err(|e| panic!("The stub identity server failed: {}", e)));
+        url
+    }
+
+    #[test]
+    fn check_admin_maps_identity_server_responses() {
+        let mut runtime = Runtime::new().unwrap();
+        let url = stub_identity_server(&mut runtime);
+        let client = Client::new();
+        let mut check = |cookie: &str| -> Result<()> {
+            runtime.block_on(check_admin(&client, Some(&url), Some(cookie.to_string())))
+        };
+
+        assert!(check("admin").is_ok());
+        match *check("user").unwrap_err().kind() {
+            ErrorKind::InsufficientPrivileges => {}
+            ref kind => panic!("Expected a 403 to mean insufficient privileges, got {}", kind),
+        }
+        match *check("expired").unwrap_err().kind() {
+            ErrorKind::AuthenticationRequired => {}
+            ref kind => panic!("Expected a 401 to mean authentication required, got {}", kind),
+        }
+    }
+}
@@ -1,30 +1,39 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+use bytes::Buf;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use failure::Fail;
 use futures::{
-    future::{err, Either},
+    future::{err, ok, Either},
     prelude::*,
+    stream::iter_ok,
 };
+use hyper::{Body, Chunk};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde_json::{self, Value};
 use tera::Context;
-use url::Url;
-use warp::http::Response;
+use url::{form_urlencoded::Serializer, percent_encoding::percent_decode, Url};
+use warp::http::{
+    header::{HeaderValue, CONTENT_TYPE},
+    status::StatusCode,
+    Response,
+};
 
-use {log_err, Error, ErrorKind, DB};
+use {
+    format_time, log_err,
+    web::{json_response, lines::Lines, Render},
+    Error, ErrorKind, FailedFilter, NewBroadcast, NewBulkEmail, NewEmail, Recipient, Result,
+    Signer, Subscriber, UnsubscribeSource, Wakeup, DB,
+};
 
 pub fn template(
     id: u32,
     context: Context,
-    auth_server_url: Option<&Url>,
     db: DB,
 ) -> impl Future<Item = Response<String>, Error = Error> {
-    if let Some(auth_server_url) = auth_server_url {
-        // TODO: Check auth
-        Either::A(
-            db.load_template(id)
-                .and_then(move |render| render(context).map(Response::new)),
-        )
-    } else {
-        Either::B(err(ErrorKind::NoAuthServer.into()))
-    }
+    db.load_template(id)
+        .and_then(move |render| render(context).map(Response::new))
 }
 
 #[derive(Deserialize)]
@@ -34,49 +43,986 @@ pub struct SendParams {
     data: String,
     email: String,
     subject: String,
+    send_at: Option<String>,
+    priority: Option<i32>,
+    idempotency_key: Option<String>,
+}
+
+pub fn send(
+    params: SendParams,
+    db: DB,
+    wakeup: Wakeup,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if serde_json::from_str::<Value>(&params.data).is_err() {
+        return Either::B(err(ErrorKind::InvalidData("data must be valid JSON").into()));
+    }
+    let send_at = match params.send_at.as_ref().map(|s| parse_send_at(s)) {
+        Some(Ok(send_at)) => Some(send_at),
+        Some(Err(e)) => return Either::B(err(e)),
+        None => None,
+    };
+    let idempotency_key = match check_idempotency_key(params.idempotency_key) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(e) => return Either::B(err(e)),
+    };
+
+    Either::A(
+        db.enqueue(NewEmail {
+            mailing_list: params.mailing_list,
+            template: params.template,
+            email: params.email,
+            subject: params.subject,
+            data: params.data,
+            send_at,
+            priority: params.priority.unwrap_or(0),
+            idempotency_key,
+        }).map(move |id| {
+            wakeup.wake();
+            json_response(StatusCode::ACCEPTED, &json!({ "id": id }))
+        }),
+    )
 }
 
-pub fn send(params: SendParams, db: DB) -> impl Future<Item = Response<String>, Error = Error> {
-    Ok(unimplemented!()).into_future()
+#[derive(Deserialize)]
+pub struct BulkSendParams {
+    mailing_list: String,
+    template: String,
+    subject: String,
+    send_at: Option<String>,
+    priority: Option<i32>,
+    recipients: Vec<BulkRecipient>,
 }
 
 #[derive(Deserialize)]
-pub struct UnsubscribeParams {
+pub struct BulkRecipient {
     email: String,
+    data: Value,
 }
 
-pub fn unsubscribe_get(
-    mailing_list_id: u32,
-    params: UnsubscribeParams,
+pub fn send_bulk(
+    params: BulkSendParams,
     db: DB,
-    render: Arc<impl Fn(&str, Context) -> Response<String>>,
+    wakeup: Wakeup,
 ) -> impl Future<Item = Response<String>, Error = Error> {
-    db.get_mailing_list_name(mailing_list_id).map(move |name| {
-        render(
-            "unsubscribe.html",
-            context! { email: params.email, name: name },
+    let send_at = match params.send_at.as_ref().map(|s| parse_send_at(s)) {
+        Some(Ok(send_at)) => Some(send_at),
+        Some(Err(e)) => return Either::B(err(e)),
+        None => None,
+    };
+    let new = NewBulkEmail {
+        mailing_list: params.mailing_list,
+        template: params.template,
+        subject: params.subject,
+        send_at,
+        priority: params.priority.unwrap_or(0),
+        recipients: Vec::new(),
+    };
+
+    let mut seen = HashSet::new();
+    Either::A(
+        enqueue_recipients(new, params.recipients, &mut seen, &db, wakeup).map(|results| {
+            json_response(StatusCode::ACCEPTED, &json!({ "results": results }))
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct StreamSendParams {
+    mailing_list: String,
+    template: String,
+    subject: String,
+    send_at: Option<String>,
+    priority: Option<i32>,
+}
+
+/// The number of lines of a streamed send that are queued at a time.
+const STREAM_BATCH_SIZE: usize = 500;
+
+pub fn send_stream<S, B>(
+    params: StreamSendParams,
+    body: S,
+    db: DB,
+    wakeup: Wakeup,
+) -> impl Future<Item = Response<Body>, Error = Error>
+where
+    S: Stream<Item = B, Error = ::warp::Error> + Send + 'static,
+    B: Buf + Send + 'static,
+{
+    let send_at = match params.send_at.as_ref().map(|s| parse_send_at(s)) {
+        Some(Ok(send_at)) => Some(send_at),
+        Some(Err(e)) => return Either::B(err(e)),
+        None => None,
+    };
+    let new = NewBulkEmail {
+        mailing_list: params.mailing_list,
+        template: params.template,
+        subject: params.subject,
+        send_at,
+        priority: params.priority.unwrap_or(0),
+        recipients: Vec::new(),
+    };
+
+    Either::A(
+        db.check_template_exists(new.mailing_list.clone(), new.template.clone())
+            .map(move |()| {
+                let mut seen = HashSet::new();
+                let results = Lines::new(body)
+                    .zip(iter_ok(1..))
+                    .chunks(STREAM_BATCH_SIZE)
+                    .and_then(move |lines| {
+                        enqueue_lines(new.clone(), lines, &mut seen, &db, wakeup.clone())
+                    })
+                    .map(Chunk::from)
+                    .map_err(|e| {
+                        error!("Streamed send failed partway through: {}", e);
+                        e.compat()
+                    });
+
+                let mut res = Response::new(Body::wrap_stream(results));
+                res.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/x-ndjson"),
+                );
+                res
+            }),
+    )
+}
+
+/// Queues the recipients on a batch of lines of a streamed send, returning the result for each
+/// line as newline-delimited JSON. Blank lines are skipped. `seen` holds the addresses of the
+/// earlier batches, as for `enqueue_recipients`.
+fn enqueue_lines(
+    new: NewBulkEmail,
+    lines: Vec<(Vec<u8>, u64)>,
+    seen: &mut HashSet<String>,
+    db: &DB,
+    wakeup: Wakeup,
+) -> impl Future<Item = String, Error = Error> {
+    let mut parsed = Vec::with_capacity(lines.len());
+    let mut recipients = Vec::with_capacity(lines.len());
+    for (line, n) in lines {
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        match serde_json::from_slice::<BulkRecipient>(&line) {
+            Ok(recipient) => {
+                recipients.push(recipient);
+                parsed.push((n, None));
+            }
+            Err(e) => parsed.push((n, Some(e.to_string()))),
+        }
+    }
+
+    enqueue_recipients(new, recipients, seen, db, wakeup).map(move |results| {
+        let mut results = results.into_iter();
+        let mut out = String::new();
+        for (n, error) in parsed {
+            let mut result = match error {
+                Some(error) => json!({ "status": "invalid", "error": error }),
+                None => results.next().unwrap_or(Value::Null),
+            };
+            if let Some(result) = result.as_object_mut() {
+                result.insert("line".to_string(), json!(n));
+            }
+            out.push_str(&result.to_string());
+            out.push('\n');
+        }
+        out
+    })
+}
+
+/// Queues an email for each recipient whose address is valid, and who hasn't unsubscribed,
+/// returning the result for each recipient in order. `seen` holds the lowercased addresses that
+/// were already queued by this request, so that an address given twice is only sent one email.
+fn enqueue_recipients(
+    mut new: NewBulkEmail,
+    recipients: Vec<BulkRecipient>,
+    seen: &mut HashSet<String>,
+    db: &DB,
+    wakeup: Wakeup,
+) -> impl Future<Item = Vec<Value>, Error = Error> {
+    let mut addresses = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let status = if !is_valid_address(&recipient.email) {
+            Some("invalid_address")
+        } else if !seen.insert(recipient.email.to_lowercase()) {
+            Some("duplicate")
+        } else {
+            new.recipients.push(Recipient {
+                email: recipient.email.clone(),
+                data: recipient.data.to_string(),
+            });
+            None
+        };
+        addresses.push((recipient.email, status));
+    }
+
+    db.enqueue_bulk(new).map(move |ids| {
+        let mut ids = ids.into_iter();
+        let mut queued = false;
+        let results = addresses
+            .into_iter()
+            .map(|(email, status)| {
+                if let Some(status) = status {
+                    return json!({ "email": email, "status": status });
+                }
+                match ids.next() {
+                    Some(Some(id)) => {
+                        queued = true;
+                        json!({ "email": email, "status": "queued", "id": id })
+                    }
+                    _ => json!({ "email": email, "status": "unsubscribed" }),
+                }
+            })
+            .collect::<Vec<_>>();
+        if queued {
+            wakeup.wake();
+        }
+        results
+    })
+}
+
+#[derive(Deserialize)]
+pub struct BroadcastParams {
+    mailing_list: String,
+    template: String,
+    data: String,
+    subject: String,
+    send_at: Option<String>,
+    priority: Option<i32>,
+    idempotency_key: Option<String>,
+}
+
+pub fn broadcast(
+    params: BroadcastParams,
+    db: DB,
+    wakeup: Wakeup,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if serde_json::from_str::<Value>(&params.data).is_err() {
+        return Either::B(err(ErrorKind::InvalidData("data must be valid JSON").into()));
+    }
+    let send_at = match params.send_at.as_ref().map(|s| parse_send_at(s)) {
+        Some(Ok(send_at)) => Some(send_at),
+        Some(Err(e)) => return Either::B(err(e)),
+        None => None,
+    };
+    let idempotency_key = match check_idempotency_key(params.idempotency_key) {
+        Ok(idempotency_key) => idempotency_key,
+        Err(e) => return Either::B(err(e)),
+    };
+
+    Either::A(
+        db.broadcast(NewBroadcast {
+            mailing_list: params.mailing_list,
+            template: params.template,
+            subject: params.subject,
+            data: params.data,
+            send_at,
+            priority: params.priority.unwrap_or(0),
+            idempotency_key,
+        }).map(move |queued| {
+            if queued > 0 {
+                wakeup.wake();
+            }
+            json_response(StatusCode::ACCEPTED, &json!({ "queued": queued }))
+        }),
+    )
+}
+
+#[derive(Default, Deserialize)]
+pub struct FailedParams {
+    mailing_list: Option<String>,
+    template: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    error: Option<String>,
+    #[serde(default)]
+    all: bool,
+}
+
+impl FailedParams {
+    /// Returns whether no filters were given, so the parameters match every failed email.
+    fn matches_all(&self) -> bool {
+        self.mailing_list.is_none()
+            && self.template.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.error.is_none()
+    }
+
+    fn into_filter(self) -> Result<FailedFilter> {
+        Ok(FailedFilter {
+            mailing_list: self.mailing_list,
+            template: self.template,
+            since: self.since.map(|s| parse_time(&s)).transpose()?,
+            until: self.until.map(|s| parse_time(&s)).transpose()?,
+            error: self.error,
+        })
+    }
+}
+
+pub fn failed_list(
+    params: FailedParams,
+    db: DB,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    params
+        .into_filter()
+        .into_future()
+        .and_then(move |filter| db.list_failed(filter))
+        .map(|emails| {
+            let emails = emails
+                .into_iter()
+                .map(|email| {
+                    json!({
+                        "id": email.id,
+                        "mailing_list": email.mailing_list,
+                        "template": email.template,
+                        "email": email.to_addr,
+                        "subject": email.subject,
+                        "created_at": format_time(email.created_at),
+                        "attempts": email.attempts,
+                        "last_error": email.last_error,
+                    })
+                })
+                .collect::<Vec<_>>();
+            json_response(StatusCode::OK, &emails)
+        })
+}
+
+pub fn failed_requeue(
+    params: FailedParams,
+    db: DB,
+    wakeup: Wakeup,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    params
+        .into_filter()
+        .into_future()
+        .and_then(move |filter| db.requeue_failed(filter))
+        .map(move |count| {
+            wakeup.wake();
+            json_response(StatusCode::OK, &json!({ "count": count }))
+        })
+}
+
+pub fn failed_discard(
+    params: FailedParams,
+    db: DB,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if params.matches_all() && !params.all {
+        return Either::B(err(ErrorKind::InvalidData(
+            "give at least one filter, or all=true to discard every failed email",
+        ).into()));
+    }
+
+    Either::A(
+        params
+            .into_filter()
+            .into_future()
+            .and_then(move |filter| db.discard_failed(filter))
+            .map(|count| json_response(StatusCode::OK, &json!({ "count": count }))),
+    )
+}
+
+pub fn queue_status(id: u32, db: DB) -> impl Future<Item = Response<String>, Error = Error> {
+    db.get_queue_status(id).map(move |status| {
+        json_response(
+            StatusCode::OK,
+            &json!({
+                "id": id,
+                "state": status.state,
+                "attempts": status.attempts,
+                "last_error": status.last_error,
+            }),
         )
     })
 }
 
-pub fn unsubscribe_post(
+pub fn queue_cancel(id: u32, db: DB) -> impl Future<Item = Response<String>, Error = Error> {
+    db.cancel(id).map(|()| {
+        let mut res = Response::new("".to_string());
+        *res.status_mut() = StatusCode::NO_CONTENT;
+        res
+    })
+}
+
+pub fn scheduled_list(db: DB) -> impl Future<Item = Response<String>, Error = Error> {
+    db.list_scheduled().map(|emails| {
+        let emails = emails
+            .into_iter()
+            .map(|email| {
+                json!({
+                    "id": email.id,
+                    "mailing_list": email.mailing_list,
+                    "template": email.template,
+                    "email": email.to_addr,
+                    "subject": email.subject,
+                    "send_at": format_time(email.send_at),
+                })
+            })
+            .collect::<Vec<_>>();
+        json_response(StatusCode::OK, &emails)
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RescheduleParams {
+    send_at: String,
+}
+
+pub fn scheduled_reschedule(
+    id: u32,
+    params: RescheduleParams,
+    db: DB,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    parse_send_at(&params.send_at)
+        .into_future()
+        .and_then(move |send_at| db.reschedule(id, send_at))
+        .map(|()| {
+            let mut res = Response::new("".to_string());
+            *res.status_mut() = StatusCode::NO_CONTENT;
+            res
+        })
+}
+
+/// The number of subscribers listed at a time, if the request doesn't say.
+const DEFAULT_SUBSCRIBER_LIMIT: i64 = 100;
+
+/// The most subscribers that can be listed at a time.
+const MAX_SUBSCRIBER_LIMIT: i64 = 1000;
+
+#[derive(Default, Deserialize)]
+pub struct SubscriberListParams {
+    after: Option<u32>,
+    limit: Option<i64>,
+}
+
+pub fn subscriber_list(
     mailing_list_id: u32,
-    params: UnsubscribeParams,
+    params: SubscriberListParams,
+    db: DB,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SUBSCRIBER_LIMIT)
+        .max(1)
+        .min(MAX_SUBSCRIBER_LIMIT);
+    db.list_subscribers(mailing_list_id, params.after, limit)
+        .map(move |subscribers| {
+            let next = if subscribers.len() as i64 == limit {
+                subscribers.last().map(|subscriber| subscriber.id)
+            } else {
+                None
+            };
+            let subscribers = subscribers
+                .into_iter()
+                .map(subscriber_json)
+                .collect::<Vec<_>>();
+            json_response(
+                StatusCode::OK,
+                &json!({ "subscribers": subscribers, "next": next }),
+            )
+        })
+}
+
+#[derive(Deserialize)]
+pub struct AddSubscriberParams {
+    email: String,
+    attributes: Option<Value>,
+}
+
+pub fn subscriber_add(
+    mailing_list_id: u32,
+    params: AddSubscriberParams,
+    db: DB,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !is_valid_address(&params.email) {
+        return Either::B(err(ErrorKind::InvalidData("email must be an email address").into()));
+    }
+    let attributes = match params.attributes {
+        Some(Value::Object(attributes)) => Value::Object(attributes),
+        Some(Value::Null) | None => json!({}),
+        Some(_) => {
+            return Either::B(err(
+                ErrorKind::InvalidData("attributes must be a JSON object").into(),
+            ))
+        }
+    };
+
+    Either::A(
+        db.add_subscriber(mailing_list_id, params.email, attributes.to_string())
+            .map(|subscriber| json_response(StatusCode::CREATED, &subscriber_json(subscriber))),
+    )
+}
+
+pub fn subscriber_get(
+    mailing_list_id: u32,
+    email: String,
     db: DB,
-    render: Arc<impl Fn(&str, Context) -> Response<String>>,
 ) -> impl Future<Item = Response<String>, Error = Error> {
-    db.get_mailing_list_name(mailing_list_id)
-        .join(db.unsubscribe(params.email.clone(), mailing_list_id))
+    decode_segment(&email)
+        .into_future()
+        .and_then(move |email| db.get_subscriber(mailing_list_id, email))
+        .map(|subscriber| json_response(StatusCode::OK, &subscriber_json(subscriber)))
+}
+
+pub fn subscriber_remove(
+    mailing_list_id: u32,
+    email: String,
+    db: DB,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    decode_segment(&email)
+        .into_future()
+        .and_then(move |email| db.remove_subscriber(mailing_list_id, email))
+        .map(|()| {
+            let mut res = Response::new(String::new());
+            *res.status_mut() = StatusCode::NO_CONTENT;
+            res
+        })
+}
+
+/// Converts a subscriber to JSON, for use in responses.
+fn subscriber_json(subscriber: Subscriber) -> Value {
+    let attributes = subscriber
+        .attributes
+        .and_then(|attributes| serde_json::from_str(&attributes).ok())
+        .unwrap_or_else(|| json!({}));
+    json!({
+        "id": subscriber.id,
+        "email": subscriber.email,
+        "attributes": attributes,
+        "created_at": format_time(subscriber.created_at),
+        "confirmed": subscriber.confirmed,
+    })
+}
+
+/// The length of the tokens in subscription confirmation links.
+const CONFIRM_TOKEN_LENGTH: usize = 32;
+
+#[derive(Clone, Deserialize)]
+pub struct PreferencesParams {
+    email: String,
+    expires: Option<i64>,
+    #[serde(default)]
+    token: String,
+}
+
+impl PreferencesParams {
+    /// Reads the parameters from a submitted preferences form.
+    fn from_form(form: &HashMap<String, String>) -> Option<PreferencesParams> {
+        Some(PreferencesParams {
+            email: form.get("email")?.clone(),
+            expires: match form.get("expires") {
+                Some(expires) => Some(expires.parse().ok()?),
+                None => None,
+            },
+            token: form.get("token").cloned().unwrap_or_default(),
+        })
+    }
+
+    /// Checks the token in the parameters.
+    fn verify(&self, signer: &Signer) -> bool {
+        signer.verify_preferences_token(&self.email, self.expires, &self.token)
+    }
+}
+
+pub fn preferences_get(
+    params: PreferencesParams,
+    db: DB,
+    signer: Signer,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !params.verify(&signer) {
+        return Either::B(ok(render("preferences-err.html", Context::new())));
+    }
+
+    Either::A(
+        db.get_preferences(params.email.clone())
+            .map(move |prefs| render_preferences(&*render, params, prefs, false)),
+    )
+}
+
+/// Saves the preferences submitted from the preferences page. Of the mailing lists named in the
+/// `lists` field, i.e. the ones the page showed, every one with a checked `list-<id>` box is kept,
+/// and the rest are unsubscribed from; a checked `unsubscribe_all` box opts out of all mail.
+pub fn preferences_post(
+    form: HashMap<String, String>,
+    db: DB,
+    signer: Signer,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    let params = match PreferencesParams::from_form(&form) {
+        Some(ref params) if params.verify(&signer) => params.clone(),
+        _ => return Either::B(ok(render("preferences-err.html", Context::new()))),
+    };
+    let shown = form
+        .get("lists")
+        .map(|lists| lists.split(',').filter_map(|id| id.parse().ok()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let subscribed = form
+        .keys()
+        .filter(|key| key.starts_with("list-"))
+        .filter_map(|key| key["list-".len()..].parse().ok())
+        .collect();
+    let unsubscribe_all = form.contains_key("unsubscribe_all");
+
+    let db2 = db.clone();
+    Either::A(
+        db.set_preferences(params.email.clone(), shown, subscribed, unsubscribe_all)
+            .and_then(move |()| {
+                db2.get_preferences(params.email.clone())
+                    .map(move |prefs| (params, prefs))
+            })
+            .then(move |r| {
+                Ok(match r {
+                    Ok((params, prefs)) => render_preferences(&*render, params, prefs, true),
+                    Err(e) => {
+                        log_err(e.into());
+                        render("preferences-err.html", Context::new())
+                    }
+                })
+            }),
+    )
+}
+
+/// Renders the preferences page.
+fn render_preferences(
+    render: &Fn(&str, Context) -> Response<String>,
+    params: PreferencesParams,
+    (lists, unsubscribed_all): (Vec<(u32, String, bool)>, bool),
+    saved: bool,
+) -> Response<String> {
+    let list_ids = lists
+        .iter()
+        .map(|&(id, _, _)| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let lists = lists
+        .into_iter()
+        .map(|(id, name, subscribed)| json!({ "id": id, "name": name, "subscribed": subscribed }))
+        .collect::<Vec<_>>();
+    render(
+        "preferences.html",
+        context! {
+            email: params.email,
+            expires: params.expires,
+            list_ids: list_ids,
+            lists: lists,
+            saved: saved,
+            token: params.token,
+            unsubscribed_all: unsubscribed_all,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeParams {
+    email: String,
+}
+
+pub fn subscribe_post(
+    mailing_list_id: u32,
+    params: SubscribeParams,
+    db: DB,
+    base_url: Arc<Url>,
+    wakeup: Wakeup,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    let token = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CONFIRM_TOKEN_LENGTH)
+        .collect::<String>();
+    let confirm_url = if is_valid_address(&params.email) {
+        base_url
+            .join(&format!("confirm/{}", token))
+            .map_err(Error::from)
+    } else {
+        Err(ErrorKind::InvalidData("email must be an email address").into())
+    };
+
+    let email = params.email.clone();
+    confirm_url
+        .into_future()
+        .and_then(move |url| db.subscribe(mailing_list_id, email, token, url.to_string()))
         .then(move |r| {
             Ok(match r {
-                Ok((name, ())) => render(
-                    "unsubscribe-ok.html",
-                    context! { email: params.email, name: name },
-                ),
+                Ok((name, queued)) => {
+                    if queued.is_some() {
+                        wakeup.wake();
+                    }
+                    render(
+                        "subscribe-ok.html",
+                        context! { email: params.email, name: name },
+                    )
+                }
                 Err(e) => {
                     log_err(e.into());
-                    render("unsubscribe-err.html", Context::new())
+                    render("subscribe-err.html", Context::new())
                 }
             })
         })
 }
+
+pub fn confirm_get(
+    token: String,
+    db: DB,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    db.confirm_subscription(token).then(move |r| {
+        Ok(match r {
+            Ok(Some((name, email))) => {
+                render("confirm-ok.html", context! { email: email, name: name })
+            }
+            Ok(None) => render("confirm-err.html", Context::new()),
+            Err(e) => {
+                log_err(e.into());
+                render("confirm-err.html", Context::new())
+            }
+        })
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeParams {
+    email: String,
+    expires: Option<i64>,
+    #[serde(default)]
+    token: String,
+}
+
+impl UnsubscribeParams {
+    /// Checks the token in the parameters.
+    fn verify(&self, mailing_list_id: u32, signer: &Signer) -> bool {
+        signer.verify_unsubscribe_token(&self.email, mailing_list_id, self.expires, &self.token)
+    }
+}
+
+pub fn unsubscribe_get(
+    mailing_list_id: u32,
+    params: UnsubscribeParams,
+    db: DB,
+    signer: Signer,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !params.verify(mailing_list_id, &signer) {
+        return Either::B(ok(render("unsubscribe-err.html", Context::new())));
+    }
+
+    let preferences = signed_query(
+        &params.email,
+        params.expires,
+        &signer.preferences_token(&params.email, params.expires),
+    );
+    Either::A(db.get_mailing_list_name(mailing_list_id).map(move |name| {
+        render(
+            "unsubscribe.html",
+            context! {
+                email: params.email,
+                expires: params.expires,
+                mailing_list_id: mailing_list_id,
+                name: name,
+                preferences: preferences,
+                token: params.token,
+            },
+        )
+    }))
+}
+
+pub fn unsubscribe_post(
+    mailing_list_id: u32,
+    params: UnsubscribeParams,
+    db: DB,
+    signer: Signer,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !params.verify(mailing_list_id, &signer) {
+        return Either::B(ok(render("unsubscribe-err.html", Context::new())));
+    }
+
+    let resubscribe = format!(
+        "resubscribe/{}?{}",
+        mailing_list_id,
+        signed_query(
+            &params.email,
+            params.expires,
+            &signer.resubscribe_token(&params.email, mailing_list_id, params.expires),
+        )
+    );
+    Either::A(
+        db.get_mailing_list_name(mailing_list_id)
+            .join(db.unsubscribe(
+                params.email.clone(),
+                Some(mailing_list_id),
+                UnsubscribeSource::Link,
+            ))
+            .then(move |r| {
+                Ok(match r {
+                    Ok((name, _)) => render(
+                        "unsubscribe-ok.html",
+                        context! { email: params.email, name: name, resubscribe: resubscribe },
+                    ),
+                    Err(e) => {
+                        log_err(e.into());
+                        render("unsubscribe-err.html", Context::new())
+                    }
+                })
+            }),
+    )
+}
+
+/// Unsubscribes the address in a one-click unsubscribe request (RFC 8058). The body isn't read,
+/// since mail clients send it with either form encoding, and the signed query string is what
+/// authorizes the request anyway.
+pub fn unsubscribe_one_click(
+    mailing_list_id: u32,
+    params: UnsubscribeParams,
+    db: DB,
+    signer: Signer,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !params.verify(mailing_list_id, &signer) {
+        return Either::B(err(ErrorKind::InvalidToken.into()));
+    }
+
+    Either::A(
+        db.unsubscribe(params.email, Some(mailing_list_id), UnsubscribeSource::OneClick)
+            .map(|_| Response::new(String::new())),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ResubscribeParams {
+    email: String,
+    expires: Option<i64>,
+    #[serde(default)]
+    token: String,
+}
+
+impl ResubscribeParams {
+    /// Checks the token in the parameters.
+    fn verify(&self, mailing_list_id: u32, signer: &Signer) -> bool {
+        signer.verify_resubscribe_token(&self.email, mailing_list_id, self.expires, &self.token)
+    }
+}
+
+/// Serves a form asking the user to confirm that they want to be added back to the list. The
+/// resubscribe itself is done by `resubscribe_post`, so that merely fetching the link (as link
+/// scanners do) doesn't change anything.
+pub fn resubscribe_get(
+    mailing_list_id: u32,
+    params: ResubscribeParams,
+    db: DB,
+    signer: Signer,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !params.verify(mailing_list_id, &signer) {
+        return Either::B(ok(render("resubscribe-err.html", Context::new())));
+    }
+
+    Either::A(db.get_mailing_list_name(mailing_list_id).map(move |name| {
+        render(
+            "resubscribe.html",
+            context! {
+                email: params.email,
+                expires: params.expires,
+                mailing_list_id: mailing_list_id,
+                name: name,
+                token: params.token,
+            },
+        )
+    }))
+}
+
+pub fn resubscribe_post(
+    mailing_list_id: u32,
+    params: ResubscribeParams,
+    db: DB,
+    signer: Signer,
+    render: Render,
+) -> impl Future<Item = Response<String>, Error = Error> {
+    if !params.verify(mailing_list_id, &signer) {
+        return Either::B(ok(render("resubscribe-err.html", Context::new())));
+    }
+
+    Either::A(
+        db.get_mailing_list_name(mailing_list_id)
+            .join(db.resubscribe(params.email.clone(), mailing_list_id))
+            .then(move |r| {
+                Ok(match r {
+                    Ok((name, ())) => render(
+                        "resubscribe-ok.html",
+                        context! { email: params.email, name: name },
+                    ),
+                    Err(e) => {
+                        log_err(e.into());
+                        render("resubscribe-err.html", Context::new())
+                    }
+                })
+            }),
+    )
+}
+
+/// Decodes a percent-encoded path segment.
+fn decode_segment(segment: &str) -> Result<String> {
+    percent_decode(segment.as_bytes())
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|_| ErrorKind::InvalidData("path segments must be valid UTF-8").into())
+}
+
+/// Checks that an address looks like an email address. This is only a sanity check; it's up to the
+/// SMTP server to decide whether the address actually works.
+fn is_valid_address(address: &str) -> bool {
+    let mut parts = address.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !address.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        _ => false,
+    }
+}
+
+/// Builds the query string of a signed link.
+fn signed_query(email: &str, expires: Option<i64>, token: &str) -> String {
+    let mut query = Serializer::new(String::new());
+    query.append_pair("email", email);
+    if let Some(expires) = expires {
+        query.append_pair("expires", &expires.to_string());
+    }
+    query.append_pair("token", token).finish()
+}
+
+/// Checks an idempotency key given in a request. An empty key is treated as no key, since that's
+/// what an HTML form with the field left blank sends.
+fn check_idempotency_key(key: Option<String>) -> Result<Option<String>> {
+    match key {
+        Some(ref key) if key.is_empty() => Ok(None),
+        Some(ref key) if key.chars().count() > 255 => Err(ErrorKind::InvalidData(
+            "idempotency_key must be at most 255 characters",
+        ).into()),
+        key => Ok(key),
+    }
+}
+
+/// Parses the time an email should be sent at, which has to fit in a MySQL `TIMESTAMP`.
+fn parse_send_at(s: &str) -> Result<NaiveDateTime> {
+    let send_at = parse_time(s)?;
+    let earliest = NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 1);
+    let latest = NaiveDate::from_ymd(2038, 1, 19).and_hms(3, 14, 7);
+    if send_at < earliest || send_at > latest {
+        return Err(ErrorKind::InvalidData(
+            "send_at must be between 1970-01-01T00:00:01Z and 2038-01-19T03:14:07Z",
+        ).into());
+    }
+    Ok(send_at)
+}
+
+/// Parses an RFC 3339 timestamp, as used in requests.
+fn parse_time(s: &str) -> Result<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.naive_utc())
+        .map_err(|_| ErrorKind::InvalidData("timestamps must be in RFC 3339 format").into())
+}
@@ -0,0 +1,150 @@
+//! Splitting a streamed request body into lines.
+
+use std::mem;
+
+use bytes::Buf;
+use futures::prelude::*;
+
+use {Error, ErrorKind};
+
+/// The longest line accepted, in bytes.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// A stream of the lines in a body, without their line endings. Only the line currently being
+/// read is held in memory.
+pub struct Lines<S> {
+    body: S,
+    buf: Vec<u8>,
+    start: usize,
+    done: bool,
+}
+
+impl<S> Lines<S> {
+    /// Splits the given body into lines.
+    pub fn new(body: S) -> Lines<S> {
+        Lines {
+            body,
+            buf: Vec::new(),
+            start: 0,
+            done: false,
+        }
+    }
+
+    /// Takes the next complete line from the buffer, if there is one.
+    fn take_line(&mut self) -> Option<Vec<u8>> {
+        let len = self.buf[self.start..].iter().position(|&b| b == b'\n')?;
+        let mut line = self.buf[self.start..self.start + len].to_vec();
+        self.start += len + 1;
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Some(line)
+    }
+}
+
+impl<S, B> Stream for Lines<S>
+where
+    S: Stream<Item = B>,
+    S::Error: Into<Error>,
+    B: Buf,
+{
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(line) = self.take_line() {
+                return Ok(Async::Ready(Some(line)));
+            }
+            // Everything before `start` has been returned already.
+            self.buf.drain(..self.start);
+            self.start = 0;
+
+            if self.done {
+                if self.buf.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                return Ok(Async::Ready(Some(mem::replace(&mut self.buf, Vec::new()))));
+            }
+            if self.buf.len() > MAX_LINE_LENGTH {
+                return Err(ErrorKind::InvalidData("line too long").into());
+            }
+
+            match try_ready!(self.body.poll().map_err(Into::into)) {
+                Some(mut chunk) => {
+                    while chunk.has_remaining() {
+                        let n = {
+                            let bytes = chunk.bytes();
+                            self.buf.extend_from_slice(bytes);
+                            bytes.len()
+                        };
+                        chunk.advance(n);
+                    }
+                }
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::{stream::iter_ok, Future, Stream};
+
+    use super::{Lines, MAX_LINE_LENGTH};
+    use {Error, ErrorKind, Result};
+
+    /// Splits the given chunks of a body into lines.
+    fn lines(chunks: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        Lines::new(iter_ok::<_, Error>(chunks.into_iter().map(Cursor::new)))
+            .collect()
+            .wait()
+    }
+
+    /// Splits the given chunks of a body into lines, as strings.
+    fn str_lines(chunks: &[&str]) -> Vec<String> {
+        let chunks = chunks.iter().map(|chunk| chunk.as_bytes().to_vec()).collect();
+        lines(chunks)
+            .unwrap()
+            .into_iter()
+            .map(|line| String::from_utf8(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn joins_lines_split_across_chunks() {
+        assert_eq!(str_lines(&["fi", "rst\nsec", "ond\n"]), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn strips_crlf_endings() {
+        assert_eq!(str_lines(&["first\r\nsecond\r\n"]), vec!["first", "second"]);
+        assert_eq!(str_lines(&["first\r", "\nsecond\r\n"]), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn returns_final_line_without_newline() {
+        assert_eq!(str_lines(&["first\nsec", "ond"]), vec!["first", "second"]);
+        assert_eq!(str_lines(&["first\n"]), vec!["first"]);
+    }
+
+    #[test]
+    fn keeps_blank_lines() {
+        assert_eq!(str_lines(&["first\n\n", "\r\nsecond\n"]), vec!["first", "", "", "second"]);
+        assert!(str_lines(&[]).is_empty());
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let chunks = vec![vec![b'a'; MAX_LINE_LENGTH], vec![b'a'; 1], b"\n".to_vec()];
+        match *lines(chunks).unwrap_err().kind() {
+            ErrorKind::InvalidData(_) => {}
+            ref kind => panic!("Expected an overlong line to be invalid data, got {}", kind),
+        }
+
+        let chunks = vec![vec![b'a'; MAX_LINE_LENGTH], b"\n".to_vec()];
+        assert_eq!(lines(chunks).unwrap(), vec![vec![b'a'; MAX_LINE_LENGTH]]);
+    }
+}
@@ -1,99 +1,321 @@
 //! The web-serving parts.
 
+mod auth;
 mod endpoints;
+mod lines;
 
 use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use futures::prelude::*;
-use serde_json::Value;
+use hyper::Body;
+use reqwest::async::Client;
+use serde::Serialize;
+use serde_json::{self, Value};
 use tera::{self, Context, Tera};
 use url::Url;
 use warp::{
     self,
     filters::BoxedFilter,
     http::{
-        header::{HeaderValue, CONTENT_TYPE},
+        header::{HeaderValue, CONTENT_TYPE, WWW_AUTHENTICATE},
         status::StatusCode,
         Response,
     },
-    reject, Filter,
+    reject, Filter, Rejection,
 };
 
-use {log_err, web::endpoints::*, DB};
+use {
+    log_err, metrics,
+    web::{
+        auth::{admin_auth, service_auth},
+        endpoints::*,
+    },
+    Error, ErrorKind, Result, Signer, Wakeup, DB,
+};
+
+/// The largest request body accepted by `/send/bulk`, in bytes.
+const BULK_SEND_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Renders one of the web interface's HTML templates as a response.
+type Render = Arc<Fn(&str, Context) -> Response<String> + Send + Sync>;
+
+/// The state the routes share. Each request gets its own clone of it.
+#[derive(Clone)]
+struct State {
+    base_url: Arc<Url>,
+    db: DB,
+    render: Render,
+    signer: Signer,
+    wakeup: Wakeup,
+}
 
 /// Returns all the routes.
 pub fn routes(
     db: DB,
     auth_server_url: Option<Url>,
+    auth_token: String,
     base_url: Arc<Url>,
+    signer: Signer,
+    wakeup: Wakeup,
 ) -> BoxedFilter<(impl warp::Reply,)> {
-    let mut tera = Tera::default();
-    tera.register_global_function(
-        "relative_url",
-        Box::new(move |args| {
-            let s = try_get_value!("relative_url", "path", String, args["path"]);
-            let url = base_url.join(&s).map_err(|e| e.to_string())?;
-            Ok(tera::to_value(&url.to_string()).unwrap())
-        }),
-    );
-    tera.add_raw_templates(vec![
-        ("base.html", include_str!("base.html")),
-        ("index.html", include_str!("index.html")),
-        ("unsubscribe.html", include_str!("unsubscribe.html")),
-        ("unsubscribe-ok.html", include_str!("unsubscribe-ok.html")),
-        ("unsubscribe-err.html", include_str!("unsubscribe-err.html")),
-    ]).expect("Template error");
-
-    let render = Arc::new(move |name: &str, context: Context| -> Response<String> {
-        match tera.render(name, &context) {
-            Ok(html) => {
-                let mut res = Response::new(html);
-                res.headers_mut()
-                    .insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
-                res
-            }
-            Err(e) => {
-                let mut s = e
-                    .iter()
-                    .map(|e| e.to_string())
-                    .collect::<Vec<String>>()
-                    .join("\n");
-                error!("{}", s);
-
-                let mut res = Response::new(s);
-                res.headers_mut()
-                    .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
-                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                res
-            }
-        }
-    });
-    let render2 = render.clone();
-    let render3 = render.clone();
-
-    let db2 = db.clone();
-    let db3 = db.clone();
-    let db4 = db.clone();
+    let auth_token = Arc::new(auth_token);
+    let admin = admin_auth(Client::new(), auth_server_url);
+    let state = State {
+        base_url: base_url.clone(),
+        db,
+        render: renderer(base_url),
+        signer,
+        wakeup,
+    };
+    let state = warp::any().map(move || state.clone()).boxed();
 
     warp::index()
-        .map(move || render("index.html", Context::new()))
+        .and(state.clone())
+        .map(|state: State| (state.render)("index.html", Context::new()))
         .or(path!("main.css").and(warp::index()).map(|| {
             let mut res = Response::new(include_str!("main.css").to_string());
             res.headers_mut()
                 .insert(CONTENT_TYPE, HeaderValue::from_static("text/css"));
             res
         }))
-        .or(path!("send")
+        .or(path!("broadcast")
             .and(warp::index())
             .and(warp::post2())
+            .and(service_auth(auth_token.clone()))
             .and(warp::body::form())
-            .and_then(move |params| {
-                send(params, db.clone()).map_err(|e| {
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| broadcast(params, state.db, state.wakeup)),
+                )
+            }))
+        .or(path!("confirm" / String)
+            .and(warp::index())
+            .and(warp::get2())
+            .and(state.clone())
+            .and_then(|token, state: State| {
+                confirm_get(token, state.db, state.render).map_err(|e| {
                     log_err(e.into());
                     reject::server_error()
                 })
             }))
+        .or(path!("failed")
+            .and(warp::index())
+            .and(warp::get2())
+            .and(admin.clone())
+            .and(
+                warp::query::<FailedParams>()
+                    .or(warp::any().map(FailedParams::default))
+                    .unify(),
+            )
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, state: State| {
+                respond(auth.into_future().and_then(move |()| failed_list(params, state.db)))
+            }))
+        .or(path!("failed" / "requeue")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(admin.clone())
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| failed_requeue(params, state.db, state.wakeup)),
+                )
+            }))
+        .or(path!("failed" / "discard")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(admin.clone())
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, state: State| {
+                respond(auth.into_future().and_then(move |()| failed_discard(params, state.db)))
+            }))
+        .or(path!("lists" / u32 / "subscribers")
+            .and(warp::index())
+            .and(warp::get2())
+            .and(service_auth(auth_token.clone()))
+            .and(
+                warp::query::<SubscriberListParams>()
+                    .or(warp::any().map(SubscriberListParams::default))
+                    .unify(),
+            )
+            .and(state.clone())
+            .and_then(|mailing_list_id, auth: Result<()>, params, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| subscriber_list(mailing_list_id, params, state.db)),
+                )
+            }))
+        .or(path!("lists" / u32 / "subscribers")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(service_auth(auth_token.clone()))
+            .and(warp::body::json())
+            .and(state.clone())
+            .and_then(|mailing_list_id, auth: Result<()>, params, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| subscriber_add(mailing_list_id, params, state.db)),
+                )
+            }))
+        .or(path!("lists" / u32 / "subscribers" / String)
+            .and(warp::index())
+            .and(warp::get2())
+            .and(service_auth(auth_token.clone()))
+            .and(state.clone())
+            .and_then(|mailing_list_id, email, auth: Result<()>, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| subscriber_get(mailing_list_id, email, state.db)),
+                )
+            }))
+        .or(path!("lists" / u32 / "subscribers" / String)
+            .and(warp::index())
+            .and(warp::delete2())
+            .and(service_auth(auth_token.clone()))
+            .and(state.clone())
+            .and_then(|mailing_list_id, email, auth: Result<()>, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| subscriber_remove(mailing_list_id, email, state.db)),
+                )
+            }))
+        .or(path!("metrics")
+            .and(warp::index())
+            .and(warp::get2())
+            .and(service_auth(auth_token.clone()))
+            .map(|auth: Result<()>| match auth {
+                Ok(()) => {
+                    let mut res = Response::new(metrics::render());
+                    res.headers_mut().insert(
+                        CONTENT_TYPE,
+                        HeaderValue::from_static("text/plain; version=0.0.4"),
+                    );
+                    res
+                }
+                Err(e) => error_response(e),
+            }))
+        .or(path!("preferences")
+            .and(warp::index())
+            .and(warp::get2())
+            .and(warp::query())
+            .and(state.clone())
+            .and_then(|params, state: State| {
+                preferences_get(params, state.db, state.signer, state.render).map_err(|e| {
+                    log_err(e.into());
+                    reject::server_error()
+                })
+            }))
+        .or(path!("preferences")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|form, state: State| {
+                preferences_post(form, state.db, state.signer, state.render).map_err(|e| {
+                    log_err(e.into());
+                    reject::server_error()
+                })
+            }))
+        .or(path!("queue" / u32)
+            .and(warp::index())
+            .and(warp::get2())
+            .and(service_auth(auth_token.clone()))
+            .and(state.clone())
+            .and_then(|id, auth: Result<()>, state: State| {
+                respond(auth.into_future().and_then(move |()| queue_status(id, state.db)))
+            }))
+        .or(path!("queue" / u32)
+            .and(warp::index())
+            .and(warp::delete2())
+            .and(service_auth(auth_token.clone()))
+            .and(state.clone())
+            .and_then(|id, auth: Result<()>, state: State| {
+                respond(auth.into_future().and_then(move |()| queue_cancel(id, state.db)))
+            }))
+        .or(path!("resubscribe" / u32)
+            .and(warp::index())
+            .and(warp::get2())
+            .and(warp::query())
+            .and(state.clone())
+            .and_then(|mailing_list_id, params, state: State| {
+                resubscribe_get(mailing_list_id, params, state.db, state.signer, state.render)
+                    .map_err(|e| {
+                        log_err(e.into());
+                        reject::server_error()
+                    })
+            }))
+        .or(path!("resubscribe" / u32)
+            .and(warp::index())
+            .and(warp::post2())
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|mailing_list_id, params, state: State| {
+                resubscribe_post(mailing_list_id, params, state.db, state.signer, state.render)
+                    .map_err(|e| {
+                        log_err(e.into());
+                        reject::server_error()
+                    })
+            }))
+        .or(path!("scheduled")
+            .and(warp::index())
+            .and(warp::get2())
+            .and(admin.clone())
+            .and(state.clone())
+            .and_then(|auth: Result<()>, state: State| {
+                respond(auth.into_future().and_then(move |()| scheduled_list(state.db)))
+            }))
+        .or(path!("scheduled" / u32)
+            .and(warp::index())
+            .and(warp::post2())
+            .and(admin.clone())
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|id, auth: Result<()>, params, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| scheduled_reschedule(id, params, state.db)),
+                )
+            }))
+        .or(path!("send")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(service_auth(auth_token.clone()))
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, state: State| {
+                respond(auth.into_future().and_then(move |()| send(params, state.db, state.wakeup)))
+            }))
+        .or(path!("send" / "bulk")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(service_auth(auth_token.clone()))
+            .and(warp::body::content_length_limit(BULK_SEND_LIMIT))
+            .and(warp::body::json())
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, state: State| {
+                respond(
+                    auth.into_future()
+                        .and_then(move |()| send_bulk(params, state.db, state.wakeup)),
+                )
+            }))
+        .or(path!("send" / "stream")
+            .and(warp::index())
+            .and(warp::post2())
+            .and(service_auth(auth_token.clone()))
+            .and(warp::query())
+            .and(warp::body::stream())
+            .and(state.clone())
+            .and_then(|auth: Result<()>, params, body, state: State| {
+                auth.into_future()
+                    .and_then(move |()| send_stream(params, body, state.db, state.wakeup))
+                    .or_else(|e| Ok::<_, Rejection>(error_response(e).map(Body::from)))
+            }))
         .or(path!("status")
             .and(warp::index())
             .and(warp::get2())
@@ -102,6 +324,24 @@ pub fn routes(
                 *res.status_mut() = StatusCode::NO_CONTENT;
                 res
             }))
+        .or(path!("subscribe" / u32)
+            .and(warp::index())
+            .and(warp::post2())
+            .and(warp::body::form())
+            .and(state.clone())
+            .and_then(|mailing_list_id, params, state: State| {
+                subscribe_post(
+                    mailing_list_id,
+                    params,
+                    state.db,
+                    state.base_url,
+                    state.wakeup,
+                    state.render,
+                ).map_err(|e| {
+                    log_err(e.into());
+                    reject::server_error()
+                })
+            }))
         .or(path!("template" / u32)
             .and(warp::index())
             .and(
@@ -110,39 +350,170 @@ pub fn routes(
                     .or(warp::post2().and(warp::body::form::<BTreeMap<String, Value>>()))
                     .unify(),
             )
-            .and_then(move |template_id: u32, values: BTreeMap<String, Value>| {
-                let mut context = Context::new();
-                for (k, v) in values {
-                    context.add(&k, &v);
-                }
-                template(template_id, context, auth_server_url.as_ref(), db2.clone()).map_err(|e| {
-                    log_err(e.into());
-                    reject::server_error()
-                })
-            }))
+            .and(admin.clone())
+            .and(state.clone())
+            .and_then(
+                |template_id: u32,
+                 values: BTreeMap<String, Value>,
+                 auth: Result<()>,
+                 state: State| {
+                    let mut context = Context::new();
+                    for (k, v) in values {
+                        context.add(&k, &v);
+                    }
+                    respond(
+                        auth.into_future()
+                            .and_then(move |()| template(template_id, context, state.db)),
+                    )
+                },
+            ))
         .or(path!("unsubscribe" / u32)
             .and(warp::index())
             .and(warp::get2())
             .and(warp::query())
-            .and_then(move |mailing_list_id, params| {
-                unsubscribe_get(mailing_list_id, params, db3.clone(), render2.clone()).map_err(
-                    |e| {
+            .and(state.clone())
+            .and_then(|mailing_list_id, params, state: State| {
+                unsubscribe_get(mailing_list_id, params, state.db, state.signer, state.render)
+                    .map_err(|e| {
                         log_err(e.into());
                         reject::server_error()
-                    },
-                )
+                    })
+            }))
+        .or(path!("unsubscribe" / u32)
+            .and(warp::index())
+            .and(warp::post2())
+            // One-click unsubscribes are posted to the link itself, query string and all. The form
+            // on the unsubscribe page posts without the query string, so it falls through.
+            .and(warp::query())
+            .and(state.clone())
+            .and_then(|mailing_list_id, params, state: State| {
+                respond(unsubscribe_one_click(mailing_list_id, params, state.db, state.signer))
             }))
         .or(path!("unsubscribe" / u32)
             .and(warp::index())
             .and(warp::post2())
             .and(warp::body::form())
-            .and_then(move |mailing_list_id, params| {
-                unsubscribe_post(mailing_list_id, params, db4.clone(), render3.clone()).map_err(
-                    |e| {
+            .and(state.clone())
+            .and_then(|mailing_list_id, params, state: State| {
+                unsubscribe_post(mailing_list_id, params, state.db, state.signer, state.render)
+                    .map_err(|e| {
                         log_err(e.into());
                         reject::server_error()
-                    },
-                )
+                    })
             }))
         .boxed()
 }
+
+/// Returns a function that renders the web interface's HTML templates, with `relative_url`
+/// resolving paths against the base URL.
+fn renderer(base_url: Arc<Url>) -> Render {
+    let mut tera = Tera::default();
+    tera.register_global_function(
+        "relative_url",
+        Box::new(move |args| {
+            let s = try_get_value!("relative_url", "path", String, args["path"]);
+            let url = base_url.join(&s).map_err(|e| e.to_string())?;
+            Ok(tera::to_value(&url.to_string()).unwrap())
+        }),
+    );
+    tera.add_raw_templates(vec![
+        ("base.html", include_str!("base.html")),
+        ("confirm-ok.html", include_str!("confirm-ok.html")),
+        ("confirm-err.html", include_str!("confirm-err.html")),
+        ("index.html", include_str!("index.html")),
+        ("preferences.html", include_str!("preferences.html")),
+        ("preferences-err.html", include_str!("preferences-err.html")),
+        ("resubscribe.html", include_str!("resubscribe.html")),
+        ("resubscribe-ok.html", include_str!("resubscribe-ok.html")),
+        ("resubscribe-err.html", include_str!("resubscribe-err.html")),
+        ("subscribe-ok.html", include_str!("subscribe-ok.html")),
+        ("subscribe-err.html", include_str!("subscribe-err.html")),
+        ("unsubscribe.html", include_str!("unsubscribe.html")),
+        ("unsubscribe-ok.html", include_str!("unsubscribe-ok.html")),
+        ("unsubscribe-err.html", include_str!("unsubscribe-err.html")),
+    ]).expect("Template error");
+
+    Arc::new(move |name: &str, context: Context| -> Response<String> {
+        match tera.render(name, &context) {
+            Ok(html) => {
+                let mut res = Response::new(html);
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+                res
+            }
+            Err(e) => {
+                let mut s = e
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                error!("{}", s);
+
+                let mut res = Response::new(s);
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                res
+            }
+        }
+    })
+}
+
+/// Serializes a value to JSON, and makes a response with it as the body.
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<String> {
+    match serde_json::to_string(value) {
+        Ok(body) => {
+            let mut res = Response::new(body);
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            *res.status_mut() = status;
+            res
+        }
+        Err(e) => error_response(e.into()),
+    }
+}
+
+/// Makes a response describing an error. Errors that are the client's fault get a 4xx status and
+/// their message; anything else is logged and gets a 500.
+fn error_response(err: Error) -> Response<String> {
+    let status = match *err.kind() {
+        ErrorKind::AuthenticationRequired => StatusCode::UNAUTHORIZED,
+        ErrorKind::InsufficientPrivileges | ErrorKind::InvalidToken => StatusCode::FORBIDDEN,
+        ErrorKind::CannotCancel(_) | ErrorKind::SubscriberExists(_) => StatusCode::CONFLICT,
+        ErrorKind::InvalidData(_) => StatusCode::BAD_REQUEST,
+        ErrorKind::NoConfirmTemplate(_)
+        | ErrorKind::NoSuchMailingList(_)
+        | ErrorKind::NoSuchMailingListId(_)
+        | ErrorKind::NoSuchQueueItem(_)
+        | ErrorKind::NoSuchSubscriber(_)
+        | ErrorKind::NoSuchTemplate(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    let body = if status.is_server_error() {
+        let body = status.canonical_reason().unwrap_or("").to_string();
+        log_err(err.into());
+        body
+    } else {
+        err.to_string()
+    };
+
+    let mut res = Response::new(body);
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    if status == StatusCode::UNAUTHORIZED {
+        res.headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    *res.status_mut() = status;
+    res
+}
+
+/// Converts the result of an endpoint into a reply, answering errors with `error_response` rather
+/// than rejecting the request.
+fn respond<F>(fut: F) -> impl Future<Item = Response<String>, Error = Rejection>
+where
+    F: Future<Item = Response<String>, Error = Error>,
+{
+    fut.or_else(|e| Ok(error_response(e)))
+}
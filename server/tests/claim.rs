@@ -0,0 +1,100 @@
+//! Tests claiming queued emails against a real database. These are ignored by default; to run
+//! them, point `DATABASE_URL` at a scratch database with the migrations applied, and run
+//! `cargo test -- --ignored`. They claim every email that's ready to send, so don't run them
+//! against a database a mailer is sending from.
+
+extern crate futures;
+extern crate mailer;
+extern crate tokio;
+
+use std::collections::HashSet;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::{loop_fn, Future, Loop};
+use mailer::{NewEmail, DB};
+use tokio::runtime::Runtime;
+
+/// The number of emails queued for the test.
+const EMAILS: usize = 200;
+
+/// The number of emails each claimer claims at a time.
+const BATCH_SIZE: i64 = 7;
+
+/// Claims batches as the given worker until there's nothing left to claim, returning the IDs of
+/// every email claimed.
+fn claim_all(
+    db: DB,
+    worker_id: &'static str,
+) -> impl Future<Item = Vec<u32>, Error = mailer::Error> {
+    loop_fn(Vec::new(), move |mut claimed| {
+        db.get_next_batch_to_send(worker_id.to_string(), Duration::from_secs(600), BATCH_SIZE)
+            .map(move |batch| {
+                if batch.is_empty() {
+                    Loop::Break(claimed)
+                } else {
+                    claimed.extend(batch.into_iter().map(|email| email.id));
+                    Loop::Continue(claimed)
+                }
+            })
+    })
+}
+
+#[test]
+#[ignore]
+fn concurrent_claimers_never_claim_the_same_email() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let db = DB::connect(&database_url).unwrap();
+    let mut runtime = Runtime::new().unwrap();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let list = format!("claim-test-{}-{}", now.as_secs(), now.subsec_nanos());
+    runtime.block_on(db.new_mailing_list(list.clone())).unwrap();
+    let list_id = runtime
+        .block_on(db.list_mailing_lists())
+        .unwrap()
+        .into_iter()
+        .find(|&(_, ref name)| *name == list)
+        .unwrap()
+        .0;
+    runtime
+        .block_on(db.new_template(list_id, "test".to_string()))
+        .unwrap();
+
+    let mut queued = HashSet::new();
+    for i in 0..EMAILS {
+        let id = runtime
+            .block_on(db.enqueue(NewEmail {
+                mailing_list: list.clone(),
+                template: "test".to_string(),
+                email: format!("claim-test-{}@example.com", i),
+                subject: "Claim test".to_string(),
+                data: "{}".to_string(),
+                send_at: None,
+                priority: 0,
+                idempotency_key: None,
+            }))
+            .unwrap();
+        queued.insert(id);
+    }
+
+    let (a, b) = runtime
+        .block_on(claim_all(db.clone(), "claim-test-a").join(claim_all(db.clone(), "claim-test-b")))
+        .unwrap();
+    runtime
+        .block_on(db.delete_emails(queued.iter().cloned().collect()))
+        .unwrap();
+
+    let a = a.into_iter().filter(|id| queued.contains(id)).collect::<Vec<_>>();
+    let b = b.into_iter().filter(|id| queued.contains(id)).collect::<Vec<_>>();
+    let a_set = a.iter().cloned().collect::<HashSet<_>>();
+    let b_set = b.iter().cloned().collect::<HashSet<_>>();
+    assert_eq!(a.len(), a_set.len(), "A claimer claimed an email twice");
+    assert_eq!(b.len(), b_set.len(), "A claimer claimed an email twice");
+    assert!(
+        a_set.is_disjoint(&b_set),
+        "Both claimers claimed emails {:?}",
+        a_set.intersection(&b_set).collect::<Vec<_>>()
+    );
+    assert_eq!(a.len() + b.len(), EMAILS, "Some queued emails weren't claimed");
+}
@@ -0,0 +1,98 @@
+//! Tests sending confirmation emails against a real database. These are ignored by default; to
+//! run them, point `DATABASE_URL` at a scratch database with the migrations applied, and run
+//! `cargo test -- --ignored`. They claim every email that's ready to send, so don't run them
+//! against a database a mailer is sending from.
+
+extern crate diesel;
+extern crate futures;
+extern crate mailer;
+extern crate tokio;
+
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use diesel::sql_types::{Integer, Unsigned};
+use diesel::{Connection, MysqlConnection, RunQueryDsl};
+use futures::future::{loop_fn, Future, Loop};
+use mailer::{QueueState, UnsubscribeSource, DB};
+use tokio::runtime::Runtime;
+
+/// Claims batches until the email with the given ID is claimed, or there's nothing left to
+/// claim, returning whether it was claimed.
+fn claim_until(db: DB, id: u32) -> impl Future<Item = bool, Error = mailer::Error> {
+    loop_fn((), move |()| {
+        db.get_next_batch_to_send("confirmation-test".to_string(), Duration::from_secs(600), 10)
+            .map(move |batch| {
+                if batch.iter().any(|email| email.id == id) {
+                    Loop::Break(true)
+                } else if batch.is_empty() {
+                    Loop::Break(false)
+                } else {
+                    Loop::Continue(())
+                }
+            })
+    })
+}
+
+#[test]
+#[ignore]
+fn confirmations_are_sent_to_unsubscribed_addresses() {
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let db = DB::connect(&database_url).unwrap();
+    let mut runtime = Runtime::new().unwrap();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let list = format!("confirmation-test-{}-{}", now.as_secs(), now.subsec_nanos());
+    runtime.block_on(db.new_mailing_list(list.clone())).unwrap();
+    let list_id = runtime
+        .block_on(db.list_mailing_lists())
+        .unwrap()
+        .into_iter()
+        .find(|&(_, ref name)| *name == list)
+        .unwrap()
+        .0;
+    runtime
+        .block_on(db.new_template(list_id, "confirm".to_string()))
+        .unwrap();
+    let conn = MysqlConnection::establish(&database_url).unwrap();
+    diesel::sql_query(
+        "UPDATE mailer_lists SET confirm_template_id = \
+         (SELECT id FROM mailer_templates WHERE mailing_list_id = ? AND name = 'confirm') \
+         WHERE id = ?",
+    ).bind::<Unsigned<Integer>, _>(list_id)
+        .bind::<Unsigned<Integer>, _>(list_id)
+        .execute(&conn)
+        .unwrap();
+
+    // A confirmed subscriber who unsubscribed, and an address that unsubscribed before ever
+    // subscribing.
+    let subscriber = format!("{}-subscriber@example.com", list);
+    let stranger = format!("{}-stranger@example.com", list);
+    runtime
+        .block_on(db.add_subscriber(list_id, subscriber.clone(), "{}".to_string()))
+        .unwrap();
+    for email in &[&subscriber, &stranger] {
+        runtime
+            .block_on(db.unsubscribe(email.to_string(), Some(list_id), UnsubscribeSource::Link))
+            .unwrap();
+    }
+
+    for (i, email) in [&subscriber, &stranger].iter().enumerate() {
+        let token = format!("{}-{}", list, i);
+        let (_, id) = runtime
+            .block_on(db.subscribe(
+                list_id,
+                email.to_string(),
+                token.clone(),
+                format!("https://example.com/confirm/{}", token),
+            ))
+            .unwrap();
+        let id = id.expect("No confirmation email was queued");
+
+        let status = runtime.block_on(db.get_queue_status(id)).unwrap();
+        assert_eq!(status.state, QueueState::Pending);
+        let claimed = runtime.block_on(claim_until(db.clone(), id)).unwrap();
+        runtime.block_on(db.delete_emails(vec![id])).unwrap();
+        assert!(claimed, "The confirmation email to {} wasn't claimed", email);
+    }
+}